@@ -0,0 +1,405 @@
+use crate::{bcrypt_cost, HtpasswdLoad, LoadFailure, PasswordDB, PasswordHash};
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// The criteria that an audit checks a `PasswordDB` against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditPolicy {
+    /// The lowest acceptable cost for bcrypt hashes.
+    pub min_bcrypt_cost: u32,
+
+    /// User names longer than this many bytes are reported as
+    /// suspicious. Apache doesn't handle names longer than 255 bytes.
+    pub max_username_len: usize,
+}
+
+impl Default for AuditPolicy {
+    fn default() -> Self {
+        AuditPolicy {
+            min_bcrypt_cost: 10,
+            max_username_len: 255,
+        }
+    }
+}
+
+/// How bad a `Finding` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum Severity {
+    /// Worth looking into, but not necessarily a problem.
+    Warning,
+
+    /// Makes credentials (or the file) easier to attack than they
+    /// should be.
+    Error,
+}
+
+/// Password storage schemes that are too weak to be used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum Scheme {
+    /// Apache's iterated MD5 (`$apr1$`).
+    MD5,
+
+    /// Unsalted SHA1 (`{SHA}`).
+    SHA1,
+
+    /// Traditional DES-based crypt(3), or a plaintext password; the
+    /// two can't be told apart.
+    Crypt,
+}
+
+/// A problem found in an audit.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize),
+    serde(tag = "kind", rename_all = "snake_case")
+)]
+pub enum Problem {
+    /// The user's password is stored with an insecure scheme.
+    InsecureScheme { scheme: Scheme },
+
+    /// The user's password is hashed with bcrypt at a cost below the
+    /// policy's minimum.
+    LowCost { cost: u32 },
+
+    /// The user's bcrypt hash doesn't indicate a cost.
+    MalformedHash,
+
+    /// The user has more than one entry in the file. Only the last
+    /// one takes effect with this crate, but Apache uses the first, so
+    /// this is an error.
+    Duplicate,
+
+    /// The user name has surrounding or embedded whitespace.
+    UsernameWhitespace,
+
+    /// The user name contains control characters.
+    UsernameControlCharacters,
+
+    /// The user name starts with `#`, so the entry looks like a
+    /// comment.
+    UsernameCommentMarker,
+
+    /// The user name is longer than the policy allows.
+    UsernameTooLong { len: usize },
+
+    /// The file can be read by users other than its owner (the
+    /// permission bits are given in `mode`).
+    FileReadable { mode: u32 },
+
+    /// The file can be modified by users other than its owner.
+    FileWritable { mode: u32 },
+}
+
+impl Problem {
+    /// Returns how bad the problem is.
+    pub fn severity(&self) -> Severity {
+        use Problem::*;
+        match self {
+            InsecureScheme { .. }
+            | LowCost { .. }
+            | MalformedHash
+            | Duplicate
+            | FileWritable { .. } => Severity::Error,
+            FileReadable { mode } if mode & 0o004 != 0 => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        use Problem::*;
+        match self {
+            InsecureScheme { scheme } => write!(f, "password is stored with insecure {:?}", scheme),
+            LowCost { cost } => write!(f, "bcrypt cost {} is too low", cost),
+            MalformedHash => write!(f, "bcrypt hash is malformed"),
+            Duplicate => write!(f, "user has multiple entries"),
+            UsernameWhitespace => write!(f, "user name contains whitespace"),
+            UsernameControlCharacters => write!(f, "user name contains control characters"),
+            UsernameCommentMarker => write!(f, "user name starts with `#`"),
+            UsernameTooLong { len } => write!(f, "user name is {} bytes long", len),
+            FileReadable { mode } => write!(f, "file is readable by others (mode {:o})", mode),
+            FileWritable { mode } => write!(f, "file is writable by others (mode {:o})", mode),
+        }
+    }
+}
+
+/// A problem found in an audit, together with the user that it
+/// concerns (if any).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Finding {
+    pub user: Option<String>,
+    pub severity: Severity,
+    pub problem: Problem,
+}
+
+impl Finding {
+    fn new(user: Option<&str>, problem: Problem) -> Finding {
+        Finding {
+            user: user.map(str::to_string),
+            severity: problem.severity(),
+            problem,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match &self.user {
+            Some(user) => write!(f, "{}: user {:?}: {}", severity, user, self.problem),
+            None => write!(f, "{}: {}", severity, self.problem),
+        }
+    }
+}
+
+/// The results of auditing a `PasswordDB`.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AuditReport {
+    /// Everything that was found, ordered by user name.
+    pub findings: Vec<Finding>,
+}
+
+impl AuditReport {
+    /// Returns the severity of the worst finding, if there are any.
+    pub fn worst(&self) -> Option<Severity> {
+        self.findings.iter().map(|f| f.severity).max()
+    }
+}
+
+/// Returns the problems with a user's name and stored password.
+pub(crate) fn entry_problems(
+    user: &str,
+    hash: &PasswordHash,
+    policy: &AuditPolicy,
+) -> Vec<Problem> {
+    let mut problems = vec![];
+    match hash {
+        PasswordHash::Bcrypt(hash) => match bcrypt_cost(hash) {
+            Some(cost) if cost < policy.min_bcrypt_cost => problems.push(Problem::LowCost { cost }),
+            Some(_) => {}
+            None => problems.push(Problem::MalformedHash),
+        },
+        PasswordHash::MD5(_) => problems.push(Problem::InsecureScheme {
+            scheme: Scheme::MD5,
+        }),
+        PasswordHash::SHA1(_) => problems.push(Problem::InsecureScheme {
+            scheme: Scheme::SHA1,
+        }),
+        PasswordHash::Crypt(_) => problems.push(Problem::InsecureScheme {
+            scheme: Scheme::Crypt,
+        }),
+    }
+
+    if user.starts_with('#') {
+        problems.push(Problem::UsernameCommentMarker);
+    }
+    if user.chars().any(char::is_whitespace) {
+        problems.push(Problem::UsernameWhitespace);
+    }
+    if user.chars().any(char::is_control) {
+        problems.push(Problem::UsernameControlCharacters);
+    }
+    if user.len() > policy.max_username_len {
+        problems.push(Problem::UsernameTooLong { len: user.len() });
+    }
+    problems
+}
+
+impl PasswordDB {
+    /// Checks every entry in the database against the policy and
+    /// reports users whose passwords are stored insecurely, whose
+    /// names look suspicious, or that were listed more than once.
+    pub fn audit(&self, policy: &AuditPolicy) -> AuditReport {
+        let mut users: Vec<&String> = self.entries.keys().collect();
+        users.sort();
+        let mut findings = vec![];
+        for user in users {
+            if self.duplicates.contains(user) {
+                findings.push(Finding::new(Some(user), Problem::Duplicate));
+            }
+            for problem in entry_problems(user, &self.entries[user], policy) {
+                findings.push(Finding::new(Some(user), problem));
+            }
+        }
+        AuditReport { findings }
+    }
+}
+
+/// Returns problems with the permissions of a file that holds
+/// credentials.
+#[cfg(unix)]
+pub(crate) fn permission_problems(metadata: &std::fs::Metadata) -> Vec<Problem> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode() & 0o777;
+    let mut problems = vec![];
+    if mode & 0o044 != 0 {
+        problems.push(Problem::FileReadable { mode });
+    }
+    if mode & 0o022 != 0 {
+        problems.push(Problem::FileWritable { mode });
+    }
+    problems
+}
+
+#[cfg(not(unix))]
+pub(crate) fn permission_problems(_metadata: &std::fs::Metadata) -> Vec<Problem> {
+    vec![]
+}
+
+/// Loads the `.htpasswd` file at `path` and audits it, including the
+/// file's permissions: Files that can be read by anyone but their
+/// owner expose the hashes to offline guessing.
+pub fn audit_file<P: AsRef<Path>>(
+    path: P,
+    policy: &AuditPolicy,
+) -> Result<AuditReport, LoadFailure> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let db = if metadata.len() == 0 {
+        PasswordDB::default()
+    } else {
+        file.load_htpasswd()?
+    };
+    let mut report = AuditReport {
+        findings: permission_problems(&metadata)
+            .into_iter()
+            .map(|problem| Finding::new(None, problem))
+            .collect(),
+    };
+    report.findings.extend(db.audit(policy).findings);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_htpasswd_str;
+
+    fn problems_of(report: &AuditReport, user: &str) -> Vec<Problem> {
+        report
+            .findings
+            .iter()
+            .filter(|f| f.user.as_ref().map(String::as_str) == Some(user))
+            .map(|f| f.problem.clone())
+            .collect()
+    }
+
+    #[test]
+    fn schemes_and_costs() {
+        let db = parse_htpasswd_str(
+            "asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96
+bsf:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=
+csf:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/
+dsf:rqXexS6ZhobKA
+esf:$2y$foobar",
+        )
+        .unwrap();
+        let report = db.audit(&AuditPolicy::default());
+        assert_eq!(
+            vec![Problem::LowCost { cost: 5 }],
+            problems_of(&report, "asf")
+        );
+        assert_eq!(
+            vec![Problem::InsecureScheme {
+                scheme: Scheme::SHA1
+            }],
+            problems_of(&report, "bsf")
+        );
+        assert_eq!(
+            vec![Problem::InsecureScheme {
+                scheme: Scheme::MD5
+            }],
+            problems_of(&report, "csf")
+        );
+        assert_eq!(
+            vec![Problem::InsecureScheme {
+                scheme: Scheme::Crypt
+            }],
+            problems_of(&report, "dsf")
+        );
+        assert_eq!(vec![Problem::MalformedHash], problems_of(&report, "esf"));
+        assert_eq!(Some(Severity::Error), report.worst());
+
+        let lenient = AuditPolicy {
+            min_bcrypt_cost: 5,
+            ..AuditPolicy::default()
+        };
+        assert_eq!(
+            Vec::<Problem>::new(),
+            problems_of(&db.audit(&lenient), "asf")
+        );
+    }
+
+    #[test]
+    fn duplicates_and_usernames() {
+        let hash = "$2y$12$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96";
+        let db = parse_htpasswd_str(&format!(
+            "asf:{0}\nasf:{0}\n#bsf:{0}\n csf:{0}\ndsf\t:{0}\nesf:{0}",
+            hash
+        ))
+        .unwrap();
+        let report = db.audit(&AuditPolicy::default());
+        assert_eq!(vec![Problem::Duplicate], problems_of(&report, "asf"));
+        assert_eq!(
+            vec![Problem::UsernameCommentMarker],
+            problems_of(&report, "#bsf")
+        );
+        assert_eq!(
+            vec![Problem::UsernameWhitespace],
+            problems_of(&report, " csf")
+        );
+        assert_eq!(
+            vec![
+                Problem::UsernameWhitespace,
+                Problem::UsernameControlCharacters
+            ],
+            problems_of(&report, "dsf\t")
+        );
+        assert_eq!(Vec::<Problem>::new(), problems_of(&report, "esf"));
+        assert_eq!(Some(Severity::Error), report.worst());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_permissions() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("htpasswd-audit-{:x}", rand::random::<u64>()));
+        fs::write(&path, "").unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(
+            AuditReport::default(),
+            audit_file(&path, &AuditPolicy::default()).unwrap()
+        );
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let report = audit_file(&path, &AuditPolicy::default()).unwrap();
+        assert_eq!(Some(Severity::Warning), report.worst());
+        assert_eq!(
+            vec![Finding::new(None, Problem::FileReadable { mode: 0o640 })],
+            report.findings
+        );
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+        let report = audit_file(&path, &AuditPolicy::default()).unwrap();
+        assert_eq!(2, report.findings.len());
+        assert_eq!(Some(Severity::Error), report.worst());
+        fs::remove_file(path).unwrap();
+    }
+}
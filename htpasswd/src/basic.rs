@@ -0,0 +1,260 @@
+use crate::{AuthError, MalformedHeader, PasswordDB};
+use std::str;
+
+/// How the user name and password in HTTP Basic credentials are
+/// decoded. RFC 7617 lets servers ask for UTF-8, which current
+/// browsers send, but older clients send ISO-8859-1 (Latin-1).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Charset {
+    /// Accept UTF-8 only, rejecting other credentials with
+    /// `MalformedHeader::BadCharset`. This is the default.
+    Utf8,
+
+    /// Decode credentials that aren't valid UTF-8 as Latin-1.
+    Utf8OrLatin1,
+
+    /// Decode credentials in the charset of the password file:
+    /// Latin-1 for files that `parse_htpasswd_bytes` read as such, and
+    /// UTF-8 otherwise. Clients whose credentials are in the file's
+    /// charset can log in, even if it's a legacy one.
+    MatchFile,
+}
+
+impl Default for Charset {
+    fn default() -> Self {
+        Charset::Utf8
+    }
+}
+
+/// Decodes ISO-8859-1, whose code points are those of Unicode.
+pub(crate) fn latin1_decode(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| char::from(b)).collect()
+}
+
+/// Encodes `s` in ISO-8859-1, if it can be.
+pub(crate) fn latin1_encode(s: &str) -> Option<Vec<u8>> {
+    s.chars()
+        .map(|c| {
+            if (c as u32) < 0x100 {
+                Some(c as u8)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Strips spaces and tabs from both ends of `bytes`.
+fn trim(bytes: &[u8]) -> &[u8] {
+    let is_space = |c: &u8| *c == b' ' || *c == b'\t';
+    let start = bytes
+        .iter()
+        .position(|c| !is_space(c))
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|c| !is_space(c))
+        .map_or(start, |i| i + 1);
+    &bytes[start..end]
+}
+
+/// Returns the undecoded user name and password in the value of an
+/// `Authorization` header.
+fn split_basic_header(header: &[u8]) -> Result<(Vec<u8>, Vec<u8>), MalformedHeader> {
+    let header = trim(header);
+    let scheme_end = header
+        .iter()
+        .position(|&c| c == b' ')
+        .unwrap_or_else(|| header.len());
+    let (scheme, encoded) = header.split_at(scheme_end);
+    if !scheme.eq_ignore_ascii_case(b"Basic") {
+        return Err(MalformedHeader::NotBasic);
+    }
+    let encoded = trim(encoded);
+    if encoded.is_empty() {
+        return Err(MalformedHeader::Empty);
+    }
+    let decoded = base64::decode(encoded).map_err(|_| MalformedHeader::BadBase64)?;
+    let colon = decoded
+        .iter()
+        .position(|&c| c == b':')
+        .ok_or(MalformedHeader::MissingColon)?;
+    let password = decoded[colon + 1..].to_vec();
+    let mut user = decoded;
+    user.truncate(colon);
+    Ok((user, password))
+}
+
+/// Decodes credentials that are expected to be UTF-8.
+fn utf8(user: &[u8], password: &[u8]) -> Option<(String, String)> {
+    let user = str::from_utf8(user).ok()?;
+    let password = str::from_utf8(password).ok()?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Decodes the value of an `Authorization` header holding HTTP Basic
+/// credentials (RFC 7617), like `Basic YXNmOm9pbms=`, into the user
+/// name and password, which are expected to be UTF-8.
+pub fn decode_basic_header(header: &[u8]) -> Result<(String, String), MalformedHeader> {
+    let (user, password) = split_basic_header(header)?;
+    utf8(&user, &password).ok_or(MalformedHeader::BadCharset)
+}
+
+impl PasswordDB {
+    /// Like `decode_basic_header`, but decodes the user name and
+    /// password according to `charset`.
+    pub fn decode_basic_header_as(
+        &self,
+        header: &[u8],
+        charset: Charset,
+    ) -> Result<(String, String), MalformedHeader> {
+        let (user, password) = split_basic_header(header)?;
+        let latin1 = || Some((latin1_decode(&user), latin1_decode(&password)));
+        let decoded = match charset {
+            Charset::Utf8 => utf8(&user, &password),
+            Charset::Utf8OrLatin1 => utf8(&user, &password).or_else(latin1),
+            Charset::MatchFile if self.is_latin1() => latin1(),
+            Charset::MatchFile => utf8(&user, &password),
+        };
+        decoded.ok_or(MalformedHeader::BadCharset)
+    }
+
+    /// Decodes the value of an `Authorization` header with
+    /// `decode_basic_header` and validates the credentials like
+    /// `validate`, returning the user name if they are correct.
+    ///
+    /// This lets servers and gateways that don't use the `headers`
+    /// crate check HTTP Basic credentials with nothing but the raw
+    /// header value:
+    ///
+    /// ```rust
+    /// # fn main() -> Result<(), htpasswd::ParseFailure> {
+    /// // the password is "secret"
+    /// let htpasswd_contents = "username:$2y$05$xT4MzeZJQmgv7XQQGYbf/eP.ING1L9m.iOZF/yUQIYKmYnmEYkfme";
+    /// let db = htpasswd::parse_htpasswd_str(htpasswd_contents)?;
+    /// assert_eq!(
+    ///     Ok("username".to_string()),
+    ///     db.validate_basic_header(b"Basic dXNlcm5hbWU6c2VjcmV0")
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn validate_basic_header(&self, header: &[u8]) -> Result<String, AuthError> {
+        self.validate_basic_header_as(header, Charset::Utf8)
+    }
+
+    /// Like `validate_basic_header`, but decodes the user name and
+    /// password according to `charset`.
+    pub fn validate_basic_header_as(
+        &self,
+        header: &[u8],
+        charset: Charset,
+    ) -> Result<String, AuthError> {
+        let (user, password) = self.decode_basic_header_as(header, charset)?;
+        self.validate(&user, &password)?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_htpasswd_bytes, parse_htpasswd_str, BadCredentials};
+
+    fn decode(header: &str) -> Result<(String, String), MalformedHeader> {
+        decode_basic_header(header.as_bytes())
+    }
+
+    fn credentials(user: &str, password: &str) -> Result<(String, String), MalformedHeader> {
+        Ok((user.to_string(), password.to_string()))
+    }
+
+    #[test]
+    fn decoding() {
+        assert_eq!(credentials("asf", "oink"), decode("Basic YXNmOm9pbms="));
+        assert_eq!(
+            credentials("asf", "oink"),
+            decode(" basic   YXNmOm9pbms=\t")
+        );
+        // "asf:oi:nk" and ":":
+        assert_eq!(credentials("asf", "oi:nk"), decode("Basic YXNmOm9pOm5r"));
+        assert_eq!(credentials("", ""), decode("Basic Og=="));
+
+        assert_eq!(
+            Err(MalformedHeader::NotBasic),
+            decode("Bearer YXNmOm9pbms=")
+        );
+        assert_eq!(Err(MalformedHeader::NotBasic), decode("BasicYXNmOm9pbms="));
+        assert_eq!(Err(MalformedHeader::NotBasic), decode(""));
+        assert_eq!(
+            Err(MalformedHeader::BadBase64),
+            decode("Basic YXNm!m9pbms=")
+        );
+        // "asf":
+        assert_eq!(Err(MalformedHeader::MissingColon), decode("Basic YXNm"));
+        assert_eq!(Err(MalformedHeader::Empty), decode("Basic"));
+        assert_eq!(Err(MalformedHeader::Empty), decode("Basic "));
+        assert_eq!(Err(MalformedHeader::Empty), decode("basic \t "));
+        // "\xe4sf:oink":
+        assert_eq!(
+            Err(MalformedHeader::BadCharset),
+            decode("Basic 5HNmOm9pbms=")
+        );
+    }
+
+    #[test]
+    fn validation() {
+        let db =
+            parse_htpasswd_str("asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96")
+                .unwrap();
+        assert_eq!(
+            Ok("asf".to_string()),
+            db.validate_basic_header(b"Basic YXNmOm9pbms=")
+        );
+        // "asf:wrong":
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword)),
+            db.validate_basic_header(b"Basic YXNmOndyb25n")
+        );
+        assert_eq!(
+            Err(AuthError::MalformedHeader(MalformedHeader::NotBasic)),
+            db.validate_basic_header(b"Digest username=\"asf\"")
+        );
+    }
+
+    // "müller:pässword", as sent by current browsers in UTF-8 and by
+    // older ones in Latin-1:
+    const UTF8: &[u8] = b"Basic bcO8bGxlcjpww6Rzc3dvcmQ=";
+    const LATIN1: &[u8] = b"Basic bfxsbGVyOnDkc3N3b3Jk";
+
+    #[test]
+    fn charsets() {
+        use Charset::*;
+        let bad_charset = Err(AuthError::MalformedHeader(MalformedHeader::BadCharset));
+        let no_such_user = Err(AuthError::NotAuthenticated(BadCredentials::NoSuchUser));
+        let muller = || Ok("müller".to_string());
+
+        // A file written by a current htpasswd, hashing UTF-8:
+        let db = parse_htpasswd_str(
+            "müller:$2y$05$sKukuXbHSAEZ6i82T73.Se6mfOAtbWJnWGx6AHd8NIspTCWKVk3N.",
+        )
+        .unwrap();
+        assert_eq!(muller(), db.validate_basic_header(UTF8));
+        assert_eq!(bad_charset, db.validate_basic_header(LATIN1));
+        assert_eq!(muller(), db.validate_basic_header_as(LATIN1, Utf8OrLatin1));
+        assert_eq!(muller(), db.validate_basic_header_as(UTF8, MatchFile));
+        assert_eq!(bad_charset, db.validate_basic_header_as(LATIN1, MatchFile));
+
+        // A file written and hashed in Latin-1:
+        let db = parse_htpasswd_bytes(
+            b"m\xfcller:$2y$05$sKukuXbHSAEZ6i82T73.SeZkcwpBxHuLyoTfn5cGSWLLiwnmo7FLi\n",
+        )
+        .unwrap();
+        assert_eq!(muller(), db.validate_basic_header(UTF8));
+        assert_eq!(bad_charset, db.validate_basic_header(LATIN1));
+        assert_eq!(muller(), db.validate_basic_header_as(UTF8, Utf8OrLatin1));
+        assert_eq!(muller(), db.validate_basic_header_as(LATIN1, Utf8OrLatin1));
+        assert_eq!(muller(), db.validate_basic_header_as(LATIN1, MatchFile));
+        assert_eq!(no_such_user, db.validate_basic_header_as(UTF8, MatchFile));
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{AuthError, PasswordDB};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::hash_map::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type CacheKey = Vec<u8>;
+
+#[derive(Debug)]
+struct CachedCredentials {
+    /// The hash that the credentials were verified against. If the
+    /// user's hash in the database differs, the entry is stale.
+    stored_hash: String,
+    verified_at: Instant,
+}
+
+/// Credentials that aren't in a `CredentialCache`, along with the
+/// hash they will be validated against.
+pub(crate) struct Miss {
+    key: CacheKey,
+    stored_hash: Option<String>,
+}
+
+/// Remembers credentials that were recently validated successfully,
+/// so that clients which send the same credentials with every
+/// request (as HTTP Basic authentication does) don't cause a full
+/// bcrypt verification each time.
+///
+/// Entries are keyed by an HMAC-SHA256 of the user name and password,
+/// computed with a random key that is generated when the cache is
+/// created; neither the password nor an unsalted hash of it is kept
+/// in memory. An entry is used only if it is younger than the
+/// configured time-to-live and the user's hash in the database is
+/// still the one the credentials were verified against, so changing
+/// a user's password invalidates their cached credentials.
+///
+/// Failed validations are never cached.
+#[derive(Debug)]
+pub struct CredentialCache {
+    key: [u8; 32],
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<CacheKey, CachedCredentials>>,
+}
+
+impl CredentialCache {
+    /// Creates a cache that holds at most `capacity` entries, each
+    /// for at most `ttl`.
+    pub fn new(ttl: Duration, capacity: usize) -> CredentialCache {
+        CredentialCache {
+            key: rand::random(),
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the provided username and password against the
+    /// database like `PasswordDB::validate`, but returns `Ok(())`
+    /// without consulting bcrypt if the same credentials were
+    /// validated successfully against the same stored hash recently.
+    pub fn validate(&self, db: &PasswordDB, user: &str, password: &str) -> Result<(), AuthError> {
+        if let Some(miss) = self.lookup(db, user, password)? {
+            db.validate(user, password)?;
+            self.remember(miss);
+        }
+        Ok(())
+    }
+
+    /// Returns true if the credentials were validated successfully
+    /// against the user's current hash recently, so that `validate`
+    /// would accept them without consulting bcrypt.
+    pub fn contains(&self, db: &PasswordDB, user: &str, password: &str) -> bool {
+        match self.lookup(db, user, password) {
+            Ok(None) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns `None` if the credentials were validated recently, or
+    /// else what `remember` needs to add them once they have been.
+    pub(crate) fn lookup(
+        &self,
+        db: &PasswordDB,
+        user: &str,
+        password: &str,
+    ) -> Result<Option<Miss>, AuthError> {
+        // Measured like `validate` does, in the file's charset:
+        let encoded = db.encode_password(password)?;
+        db.long_password_policy().apply_bytes(&encoded)?;
+        let key = self.key_for(user, password);
+        let stored_hash = db.bcrypt_hash(user);
+        if let Some(stored_hash) = stored_hash {
+            if self.is_fresh(&key, stored_hash) {
+                return Ok(None);
+            }
+        }
+        Ok(Some(Miss {
+            key,
+            stored_hash: stored_hash.map(String::from),
+        }))
+    }
+
+    /// Adds credentials that were validated successfully.
+    pub(crate) fn remember(&self, miss: Miss) {
+        if let Some(stored_hash) = miss.stored_hash {
+            self.insert(miss.key, &stored_hash);
+        }
+    }
+
+    /// Returns the number of entries in the cache, including any that
+    /// have expired but haven't been evicted yet.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns true if the cache holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all entries from the cache.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<HashMap<CacheKey, CachedCredentials>> {
+        // Entries are only ever inserted or removed whole, so the map
+        // is consistent even if a thread panicked while holding it:
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn key_for(&self, user: &str, password: &str) -> CacheKey {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC takes keys of any size");
+        // Prefix the user name with its length, so that no two
+        // distinct user/password pairs produce the same input:
+        mac.input(&(user.len() as u64).to_le_bytes());
+        mac.input(user.as_bytes());
+        mac.input(password.as_bytes());
+        mac.result().code().to_vec()
+    }
+
+    fn is_fresh(&self, key: &[u8], stored_hash: &str) -> bool {
+        let mut entries = self.lock();
+        match entries.get(key) {
+            Some(entry) if entry.verified_at.elapsed() < self.ttl => {
+                entry.stored_hash == stored_hash
+            }
+            Some(_) => {
+                entries.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&self, key: CacheKey, stored_hash: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.lock();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let ttl = self.ttl;
+            entries.retain(|_, entry| entry.verified_at.elapsed() < ttl);
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.verified_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            CachedCredentials {
+                stored_hash: stored_hash.to_string(),
+                verified_at: Instant::now(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parse_htpasswd_bytes, parse_htpasswd_str, BadCredentials, BCRYPT_MAX_PASSWORD_LEN,
+    };
+
+    const ASF_OINK: &str = "asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96";
+    const BSF: &str = "bsf:$2y$05$9U5xoWYrBX687.C.MEhsae5LfOrlUqqMSfE2Cpo4K.jyvy3lA.Ijy";
+
+    #[test]
+    fn caches_successes_only() {
+        let db = parse_htpasswd_str(ASF_OINK).unwrap();
+        let cache = CredentialCache::new(Duration::from_secs(60), 10);
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword)),
+            cache.validate(&db, "asf", "wrong")
+        );
+        assert!(cache.is_empty());
+        assert_eq!(Ok(()), cache.validate(&db, "asf", "oink"));
+        assert_eq!(1, cache.len());
+        assert_eq!(Ok(()), cache.validate(&db, "asf", "oink"));
+        assert_eq!(1, cache.len());
+    }
+
+    #[test]
+    fn invalidated_by_hash_change() {
+        let db = parse_htpasswd_str(ASF_OINK).unwrap();
+        let cache = CredentialCache::new(Duration::from_secs(60), 10);
+        assert_eq!(Ok(()), cache.validate(&db, "asf", "oink"));
+
+        // same user, different password:
+        let changed = parse_htpasswd_str(&BSF.replacen("bsf", "asf", 1)).unwrap();
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword)),
+            cache.validate(&changed, "asf", "oink")
+        );
+
+        let removed = parse_htpasswd_str(BSF).unwrap();
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::NoSuchUser)),
+            cache.validate(&removed, "asf", "oink")
+        );
+    }
+
+    #[test]
+    fn latin1() {
+        // Twice as many bytes in UTF-8 as bcrypt takes, but not in
+        // Latin-1, which the file was read as:
+        let password = "\u{fc}".repeat(BCRYPT_MAX_PASSWORD_LEN / 2 + 4);
+        let mut contents = b"j\xfcrgen:".to_vec();
+        contents.extend(
+            bcrypt::hash(&vec![0xfc; password.chars().count()], 4)
+                .unwrap()
+                .as_bytes(),
+        );
+        let db = parse_htpasswd_bytes(&contents).unwrap();
+        let cache = CredentialCache::new(Duration::from_secs(60), 10);
+        assert_eq!(Ok(()), db.validate("j\u{fc}rgen", &password));
+        for _ in 0..2 {
+            assert_eq!(Ok(()), cache.validate(&db, "j\u{fc}rgen", &password));
+            assert_eq!(1, cache.len());
+        }
+    }
+
+    #[test]
+    fn expiry() {
+        let db = parse_htpasswd_str(ASF_OINK).unwrap();
+        let cache = CredentialCache::new(Duration::from_secs(0), 10);
+        assert_eq!(Ok(()), cache.validate(&db, "asf", "oink"));
+        assert!(!cache.is_fresh(
+            &cache.key_for("asf", "oink"),
+            db.bcrypt_hash("asf").unwrap()
+        ));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn bounded_size() {
+        let db = parse_htpasswd_str(&format!("{}\n{}", ASF_OINK, BSF)).unwrap();
+        let cache = CredentialCache::new(Duration::from_secs(60), 1);
+        assert_eq!(Ok(()), cache.validate(&db, "asf", "oink"));
+        assert_eq!(Ok(()), cache.validate(&db, "bsf", "areisntoiarnstoanrsit"));
+        assert_eq!(1, cache.len());
+        assert!(cache.is_fresh(
+            &cache.key_for("bsf", "areisntoiarnstoanrsit"),
+            db.bcrypt_hash("bsf").unwrap()
+        ));
+    }
+}
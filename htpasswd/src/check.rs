@@ -0,0 +1,243 @@
+use crate::audit::entry_problems;
+use crate::basic::latin1_decode;
+use crate::parse::parse_lines;
+use crate::{AuditPolicy, ParseErrorKind, Problem, Severity};
+use std::borrow::Cow;
+use std::collections::hash_map::HashMap;
+use std::fmt;
+use std::str;
+
+/// What a `Diagnostic` is about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticKind {
+    /// The line doesn't parse.
+    Parse(ParseErrorKind),
+
+    /// The line ends in `\r\n`. Such files load fine, but tools that
+    /// expect Unix line endings may take the `\r` to be part of the
+    /// password hash.
+    CrlfLineEnding,
+
+    /// The file isn't valid UTF-8 from this point on, so it is read
+    /// as Latin-1.
+    NotUtf8,
+
+    /// The entry on the line violates the `AuditPolicy`.
+    Problem(Problem),
+}
+
+/// A problem found by `check_htpasswd_str`, with its location.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub severity: Severity,
+
+    /// The user whose entry the diagnostic is about, if the line
+    /// could be parsed.
+    pub user: Option<String>,
+
+    /// The byte offset in the input at which the problem was found.
+    pub offset: usize,
+
+    /// The line containing the problem.
+    pub line: u32,
+
+    /// The approximate column containing the problem.
+    pub column: usize,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: ", severity)?;
+        if let Some(user) = &self.user {
+            write!(f, "user {:?}: ", user)?;
+        }
+        match &self.kind {
+            DiagnosticKind::Parse(kind) => write!(f, "{}", kind),
+            DiagnosticKind::CrlfLineEnding => write!(f, "line ends in CRLF"),
+            DiagnosticKind::NotUtf8 => write!(f, "file is not UTF-8, reading it as Latin-1"),
+            DiagnosticKind::Problem(problem) => write!(f, "{}", problem),
+        }
+    }
+}
+
+/// Checks the contents of a `.htpasswd` file for syntax errors and
+/// violations of the policy, and returns everything that was found,
+/// ordered by position.
+///
+/// Unlike `parse_htpasswd_str`, this doesn't stop at the first line
+/// that fails to parse, and it reports every user that occurs more
+/// than once at each repeated occurrence.
+pub fn check_htpasswd_str(contents: &str, policy: &AuditPolicy) -> Vec<Diagnostic> {
+    let (parsed, failures) = parse_lines(contents);
+    let mut diagnostics: Vec<Diagnostic> = failures
+        .into_iter()
+        .map(|failure| Diagnostic {
+            kind: DiagnosticKind::Parse(failure.kind),
+            severity: Severity::Error,
+            user: None,
+            offset: failure.offset,
+            line: failure.line,
+            column: failure.column,
+        })
+        .collect();
+
+    let mut seen: HashMap<&str, u32> = HashMap::new();
+    for entry in &parsed {
+        let at = |kind: DiagnosticKind, severity: Severity, column: usize| Diagnostic {
+            kind,
+            severity,
+            user: Some(entry.user.clone()),
+            offset: entry.offset + column - 1,
+            line: entry.line,
+            column,
+        };
+        // Problems with the hash are located at the hash, the rest at
+        // the user name:
+        let hash_column = entry.user.len() + 2;
+
+        if seen.insert(&entry.user, entry.line).is_some() {
+            diagnostics.push(at(
+                DiagnosticKind::Problem(Problem::Duplicate),
+                Problem::Duplicate.severity(),
+                1,
+            ));
+        }
+        for problem in entry_problems(&entry.user, &entry.hash, policy) {
+            let column = match problem {
+                Problem::InsecureScheme { .. }
+                | Problem::LowCost { .. }
+                | Problem::MalformedHash => hash_column,
+                _ => 1,
+            };
+            let severity = problem.severity();
+            diagnostics.push(at(DiagnosticKind::Problem(problem), severity, column));
+        }
+        if entry.crlf {
+            let line_len = hash_column + entry.hash.to_string().len();
+            diagnostics.push(at(
+                DiagnosticKind::CrlfLineEnding,
+                Severity::Warning,
+                line_len,
+            ));
+        }
+    }
+
+    diagnostics.sort_by_key(|d| d.offset);
+    diagnostics
+}
+
+/// Checks a `.htpasswd` file like `check_htpasswd_str`, but accepts
+/// contents that aren't UTF-8.
+///
+/// Such contents are read as Latin-1, as `parse_htpasswd_bytes` does,
+/// and a `NotUtf8` warning is reported where the invalid UTF-8
+/// starts. The returned text is what the diagnostics' positions
+/// refer to.
+pub fn check_htpasswd_bytes<'a>(
+    contents: &'a [u8],
+    policy: &AuditPolicy,
+) -> (Cow<'a, str>, Vec<Diagnostic>) {
+    let valid_up_to = match str::from_utf8(contents) {
+        Ok(contents) => {
+            return (
+                Cow::Borrowed(contents),
+                check_htpasswd_str(contents, policy),
+            )
+        }
+        Err(e) => e.valid_up_to(),
+    };
+    let before = latin1_decode(&contents[..valid_up_to]);
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let text = latin1_decode(contents);
+    let mut diagnostics = check_htpasswd_str(&text, policy);
+    diagnostics.push(Diagnostic {
+        kind: DiagnosticKind::NotUtf8,
+        severity: Severity::Warning,
+        user: None,
+        offset: before.len(),
+        line: before.matches('\n').count() as u32 + 1,
+        column: before.len() - line_start + 1,
+    });
+    diagnostics.sort_by_key(|d| d.offset);
+    (Cow::Owned(text), diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Scheme;
+
+    #[test]
+    fn everything_at_once() {
+        let contents = "asf:$2y$10$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96
+:oops
+bsf:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\r
+asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96
+";
+        let diagnostics = check_htpasswd_str(contents, &AuditPolicy::default());
+        // CRLF files load fine, so they only get a warning:
+        assert!(diagnostics
+            .iter()
+            .filter(|d| d.kind == DiagnosticKind::CrlfLineEnding)
+            .all(|d| d.severity == Severity::Warning));
+        let found: Vec<(u32, usize, DiagnosticKind)> = diagnostics
+            .into_iter()
+            .map(|d| (d.line, d.column, d.kind))
+            .collect();
+        assert_eq!(
+            vec![
+                (2, 1, DiagnosticKind::Parse(ParseErrorKind::BadUsername)),
+                (
+                    3,
+                    5,
+                    DiagnosticKind::Problem(Problem::InsecureScheme {
+                        scheme: Scheme::SHA1
+                    })
+                ),
+                (3, 38, DiagnosticKind::CrlfLineEnding),
+                (4, 1, DiagnosticKind::Problem(Problem::Duplicate)),
+                (4, 5, DiagnosticKind::Problem(Problem::LowCost { cost: 5 })),
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn not_utf8() {
+        let contents = b"asf:$2y$10$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96
+j\xfcrgen:$2y$10$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96
+";
+        let (text, diagnostics) = check_htpasswd_bytes(contents, &AuditPolicy::default());
+        assert!(text.contains("j\u{fc}rgen:"));
+        assert_eq!(
+            vec![(2, 2, DiagnosticKind::NotUtf8, Severity::Warning)],
+            diagnostics
+                .into_iter()
+                .map(|d| (d.line, d.column, d.kind, d.severity))
+                .collect::<Vec<_>>()
+        );
+
+        let (text, diagnostics) = check_htpasswd_bytes(b"asf:x\n", &AuditPolicy::default());
+        assert_eq!("asf:x\n", text);
+        assert_eq!(
+            check_htpasswd_str("asf:x\n", &AuditPolicy::default()),
+            diagnostics
+        );
+    }
+
+    #[test]
+    fn clean() {
+        assert_eq!(
+            Vec::<Diagnostic>::new(),
+            check_htpasswd_str(
+                "asf:$2y$10$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96\n",
+                &AuditPolicy::default()
+            )
+        );
+    }
+}
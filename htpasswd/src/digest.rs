@@ -0,0 +1,574 @@
+use crate::basic::latin1_decode;
+use crate::parse::parse_digest_entries;
+use crate::server::quote;
+use crate::{AuthError, BadCredentials, LoadFailure, ParseFailure};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::collections::hash_map::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::ops::Deref;
+use std::path::Path;
+use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The credentials from an Apache `htdigest` file, for HTTP Digest
+/// authentication. Each line holds a user name, a realm and the
+/// hex-encoded MD5 of `user:realm:password` (the "HA1"):
+///
+/// ```text
+/// asf:Private:d016497f7cccd2dc1ff73620692d6a1d
+/// ```
+///
+/// Since Digest authentication requires the server to know HA1, the
+/// file is only as safe as unsalted MD5: anybody who can read it can
+/// log in as any user in it. Prefer HTTP Basic authentication over
+/// TLS, with bcrypt-hashed passwords, whenever clients support it.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DigestDB {
+    /// HA1 values by realm and user name.
+    realms: HashMap<String, HashMap<String, String>>,
+}
+
+impl DigestDB {
+    /// Returns the hex-encoded HA1 stored for `user` in `realm`.
+    pub fn ha1(&self, user: &str, realm: &str) -> Option<&str> {
+        self.realms
+            .get(realm)
+            .and_then(|users| users.get(user))
+            .map(String::as_str)
+    }
+
+    /// Returns true if the user has an entry for `realm`.
+    pub fn contains_user(&self, user: &str, realm: &str) -> bool {
+        self.ha1(user, realm).is_some()
+    }
+
+    /// Checks the credentials that a client sent for a `method`
+    /// request in `realm` and returns `Ok(())` if the response was
+    /// computed from the stored HA1 and a nonce issued by `nonces`.
+    ///
+    /// Only the `MD5` algorithm with `qop=auth` is accepted. If the
+    /// response is correct but the nonce has expired, the error is
+    /// `BadCredentials::StaleNonce`, and the client should be
+    /// challenged again with `stale=true`. A nonce count that isn't
+    /// higher than the last one used with the nonce is rejected as a
+    /// replay.
+    ///
+    /// Note that the `uri` in the credentials must be compared to the
+    /// request's URI by the caller.
+    pub fn verify(
+        &self,
+        credentials: &DigestCredentials,
+        realm: &str,
+        method: &str,
+        nonces: &Nonces,
+    ) -> Result<(), AuthError> {
+        match &credentials.algorithm {
+            Some(algorithm) if !algorithm.eq_ignore_ascii_case("MD5") => {
+                return Err(BadCredentials::InvalidPassword.into());
+            }
+            _ => {}
+        }
+        let nc = match (&credentials.qop, &credentials.nc, &credentials.cnonce) {
+            (Some(qop), Some(nc), Some(_)) if qop == "auth" => {
+                u32::from_str_radix(nc, 16).map_err(|_| BadCredentials::InvalidPassword)?
+            }
+            _ => return Err(BadCredentials::InvalidPassword.into()),
+        };
+        let issued = nonces
+            .issued_at(&credentials.nonce)
+            .ok_or(BadCredentials::InvalidPassword)?;
+        if credentials.realm != realm {
+            return Err(AuthError::NotAuthenticated(BadCredentials::NoSuchUser));
+        }
+        let ha1 = self
+            .ha1(&credentials.username, realm)
+            .ok_or(BadCredentials::NoSuchUser)?;
+        let expected = credentials.expected_response(ha1, method);
+        let response = credentials.response.to_ascii_lowercase();
+        if !constant_time_eq(expected.as_bytes(), response.as_bytes()) {
+            return Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword));
+        }
+        Ok(nonces.use_count(&credentials.nonce, issued, nc)?)
+    }
+}
+
+impl FromStr for DigestDB {
+    type Err = ParseFailure;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_htdigest_str(s)
+    }
+}
+
+/// Returns the hex-encoded HA1 that `htdigest` stores for the user's
+/// password in `realm`.
+pub fn digest_ha1(user: &str, realm: &str, password: &str) -> String {
+    md5_hex(&format!("{}:{}:{}", user, realm, password))
+}
+
+/// Parses an htdigest-formatted string. Blank lines and lines
+/// starting with `#` are ignored.
+pub fn parse_htdigest_str(contents: &str) -> Result<DigestDB, ParseFailure> {
+    let mut db = DigestDB::default();
+    for (user, realm, hash) in parse_digest_entries(contents)? {
+        db.realms.entry(realm).or_default().insert(user, hash);
+    }
+    Ok(db)
+}
+
+/// Like `parse_htdigest_str`, but for the contents of a file, which
+/// are read as ISO-8859-1 (Latin-1) if they aren't valid UTF-8, like
+/// those of password files.
+pub fn parse_htdigest_bytes(contents: &[u8]) -> Result<DigestDB, ParseFailure> {
+    match str::from_utf8(contents) {
+        Ok(contents) => parse_htdigest_str(contents),
+        Err(_) => parse_htdigest_str(&latin1_decode(contents)),
+    }
+}
+
+/// Allows loading htdigest data from the same types as
+/// `HtpasswdLoad`.
+pub trait HtdigestLoad {
+    /// Reads self to the end and parses an htdigest database from it.
+    fn load_htdigest(&mut self) -> Result<DigestDB, LoadFailure>;
+}
+
+impl<T> HtdigestLoad for T
+where
+    T: Read + Sized,
+{
+    fn load_htdigest(&mut self) -> Result<DigestDB, LoadFailure> {
+        let mut contents = vec![];
+        self.read_to_end(&mut contents)?;
+        Ok(parse_htdigest_bytes(&contents)?)
+    }
+}
+
+impl HtdigestLoad for Path {
+    fn load_htdigest(&mut self) -> Result<DigestDB, LoadFailure> {
+        let contents = fs::read(self)?;
+        Ok(parse_htdigest_bytes(&contents)?)
+    }
+}
+
+/// Keeps a `DigestDB` loaded and updated in memory, like
+/// `PasswordDBSource` does for password databases.
+pub trait DigestDBSource {
+    /// Any error that can occur from attempts to load the
+    /// `DigestDB`. This will typically be `LoadFailure`.
+    type Error: Sized + Error;
+
+    /// The type returned by `get`, e.g. a lock guard.
+    type Reference: Sized + Deref<Target = Result<DigestDB, Self::Error>>;
+
+    /// Return a RAII object that yields the most recently loaded
+    /// `DigestDB`, or the error that the last load attempt resulted
+    /// in.
+    fn get(&self) -> Self::Reference;
+}
+
+impl<E: Error> DigestDBSource for Arc<Result<DigestDB, E>> {
+    type Error = E;
+    type Reference = Self;
+
+    fn get(&self) -> Self {
+        self.clone()
+    }
+}
+
+/// The parameters of an `Authorization: Digest ...` header.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DigestCredentials {
+    pub username: String,
+    pub realm: String,
+    pub nonce: String,
+    pub uri: String,
+    pub response: String,
+    pub algorithm: Option<String>,
+    pub qop: Option<String>,
+    pub nc: Option<String>,
+    pub cnonce: Option<String>,
+    pub opaque: Option<String>,
+}
+
+impl DigestCredentials {
+    /// Parses the value of an `Authorization` header, returning
+    /// `None` unless it uses the Digest scheme and has all the
+    /// required parameters.
+    pub fn parse(header: &str) -> Option<DigestCredentials> {
+        let header = header.trim_start();
+        if !header
+            .get(..7)
+            .map_or(false, |scheme| scheme.eq_ignore_ascii_case("Digest "))
+        {
+            return None;
+        }
+        let mut credentials = DigestCredentials::default();
+        let (mut username, mut realm, mut nonce, mut uri, mut response) =
+            (None, None, None, None, None);
+        for (name, value) in auth_params(&header[7..])? {
+            match name.as_str() {
+                "username" => username = Some(value),
+                "realm" => realm = Some(value),
+                "nonce" => nonce = Some(value),
+                "uri" => uri = Some(value),
+                "response" => response = Some(value),
+                "algorithm" => credentials.algorithm = Some(value),
+                "qop" => credentials.qop = Some(value),
+                "nc" => credentials.nc = Some(value),
+                "cnonce" => credentials.cnonce = Some(value),
+                "opaque" => credentials.opaque = Some(value),
+                _ => {}
+            }
+        }
+        credentials.username = username?;
+        credentials.realm = realm?;
+        credentials.nonce = nonce?;
+        credentials.uri = uri?;
+        credentials.response = response?;
+        Some(credentials)
+    }
+
+    /// Computes the response that a client knowing the password
+    /// behind `ha1` sends with these parameters for a `method`
+    /// request, as defined in RFC 7616 (or RFC 2069, if there is no
+    /// `qop`).
+    pub fn expected_response(&self, ha1: &str, method: &str) -> String {
+        let ha2 = md5_hex(&format!("{}:{}", method, self.uri));
+        match (&self.qop, &self.nc, &self.cnonce) {
+            (Some(qop), Some(nc), Some(cnonce)) => md5_hex(&format!(
+                "{}:{}:{}:{}:{}:{}",
+                ha1, self.nonce, nc, cnonce, qop, ha2
+            )),
+            _ => md5_hex(&format!("{}:{}:{}", ha1, self.nonce, ha2)),
+        }
+    }
+}
+
+/// Splits a comma-separated list of `name=value` pairs, where values
+/// may be quoted strings. Names are lower-cased.
+fn auth_params(mut input: &str) -> Option<Vec<(String, String)>> {
+    let mut params = vec![];
+    loop {
+        input = input.trim_start_matches(|c| c == ' ' || c == '\t' || c == ',');
+        if input.is_empty() {
+            return Some(params);
+        }
+        let eq = input.find('=')?;
+        let name = input[..eq].trim().to_ascii_lowercase();
+        input = input[eq + 1..].trim_start();
+        let mut value = String::new();
+        if input.starts_with('"') {
+            let mut chars = input.char_indices().skip(1);
+            loop {
+                match chars.next()? {
+                    (i, '"') => {
+                        input = &input[i + 1..];
+                        break;
+                    }
+                    (_, '\\') => value.push(chars.next()?.1),
+                    (_, c) => value.push(c),
+                }
+            }
+        } else {
+            let end = input
+                .find(|c: char| c == ',' || c.is_whitespace())
+                .unwrap_or_else(|| input.len());
+            value.push_str(&input[..end]);
+            input = &input[end..];
+        }
+        params.push((name, value));
+    }
+}
+
+/// Issues and checks the nonces that servers hand out in HTTP Digest
+/// challenges.
+///
+/// A nonce holds the time at which it was issued and some random
+/// bytes, signed with an HMAC-SHA256 key that is generated when the
+/// `Nonces` is created. Nonces can therefore be checked without
+/// remembering them, and expire after the configured lifetime. To
+/// detect replayed requests, the highest nonce count (`nc`) that each
+/// nonce was used with is remembered until it expires.
+#[derive(Debug)]
+pub struct Nonces {
+    key: [u8; 32],
+    lifetime: Duration,
+
+    /// When each nonce that was used was issued, and its highest
+    /// nonce count so far.
+    counts: Mutex<HashMap<String, (u64, u32)>>,
+}
+
+impl Nonces {
+    /// Creates a `Nonces` whose nonces expire `lifetime` after they
+    /// were issued.
+    pub fn new(lifetime: Duration) -> Nonces {
+        Nonces {
+            key: rand::random(),
+            lifetime,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a new nonce.
+    pub fn issue(&self) -> String {
+        let mut data = [0u8; 16];
+        data[..8].copy_from_slice(&now_millis().to_be_bytes());
+        data[8..].copy_from_slice(&rand::random::<[u8; 8]>());
+        let mut mac = self.mac();
+        mac.input(&data);
+        format!("{}{}", hex(&data), hex(&mac.result().code()))
+    }
+
+    /// Returns the value of a `WWW-Authenticate` header challenging
+    /// the client to authenticate for `realm` with a new nonce. If
+    /// `stale` is set, the client is told that its credentials were
+    /// correct, but its nonce expired.
+    pub fn challenge(&self, realm: &str, stale: bool) -> String {
+        format!(
+            "Digest realm={}, qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+            quote(realm),
+            self.issue(),
+            if stale { ", stale=true" } else { "" }
+        )
+    }
+
+    /// Returns when the nonce was issued (in milliseconds since the
+    /// epoch), if it was issued with our key.
+    fn issued_at(&self, nonce: &str) -> Option<u64> {
+        let bytes = unhex(nonce)?;
+        if bytes.len() != 48 {
+            return None;
+        }
+        let (data, tag) = bytes.split_at(16);
+        let mut mac = self.mac();
+        mac.input(data);
+        mac.verify(tag).ok()?;
+        let mut issued = [0u8; 8];
+        issued.copy_from_slice(&data[..8]);
+        Some(u64::from_be_bytes(issued))
+    }
+
+    /// Checks that the nonce hasn't expired yet and that `nc` is
+    /// higher than any count it was used with before.
+    fn use_count(&self, nonce: &str, issued: u64, nc: u32) -> Result<(), BadCredentials> {
+        let now = now_millis();
+        let lifetime = millis(self.lifetime);
+        if now.saturating_sub(issued) >= lifetime {
+            return Err(BadCredentials::StaleNonce);
+        }
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, highest)) = counts.get_mut(nonce) {
+            if *highest >= nc {
+                return Err(BadCredentials::InvalidPassword);
+            }
+            *highest = nc;
+            return Ok(());
+        }
+        counts.retain(|_, (issued, _)| now.saturating_sub(*issued) < lifetime);
+        counts.insert(nonce.to_string(), (issued, nc));
+        Ok(())
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC takes keys of any size")
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+fn now_millis() -> u64 {
+    millis(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+    )
+}
+
+fn md5_hex(input: &str) -> String {
+    hex(&Md5::digest(input.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REALM: &str = "testrealm@host.com";
+
+    fn db() -> DigestDB {
+        format!(
+            "Mufasa:{}:{}\n",
+            REALM,
+            digest_ha1("Mufasa", REALM, "Circle Of Life")
+        )
+        .parse()
+        .unwrap()
+    }
+
+    fn credentials(nonce: &str, nc: &str, password: &str) -> DigestCredentials {
+        let mut credentials = DigestCredentials {
+            username: "Mufasa".to_string(),
+            realm: REALM.to_string(),
+            nonce: nonce.to_string(),
+            uri: "/dir/index.html".to_string(),
+            qop: Some("auth".to_string()),
+            nc: Some(nc.to_string()),
+            cnonce: Some("0a4f113b".to_string()),
+            ..DigestCredentials::default()
+        };
+        credentials.response =
+            credentials.expected_response(&digest_ha1("Mufasa", REALM, password), "GET");
+        credentials
+    }
+
+    #[test]
+    fn rfc_example() {
+        assert_eq!(
+            "939e7578ed9e3c518a452acee763bce9",
+            digest_ha1("Mufasa", REALM, "Circle Of Life")
+        );
+        let credentials = DigestCredentials::parse(
+            r#"Digest username="Mufasa", realm="testrealm@host.com",
+                nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", uri="/dir/index.html",
+                qop=auth, nc=00000001, cnonce="0a4f113b",
+                response="6629fae49393a05397450978507c4ef1",
+                opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )
+        .unwrap();
+        assert_eq!("dcd98b7102dd2f0e8b11d0f600bfb0c093", credentials.nonce);
+        assert_eq!(Some("00000001".to_string()), credentials.nc);
+        assert_eq!(
+            credentials.response,
+            credentials.expected_response(db().ha1("Mufasa", REALM).unwrap(), "GET")
+        );
+    }
+
+    #[test]
+    fn load() {
+        let contents = format!("Mufasa:{}:{}\n", REALM, digest_ha1("Mufasa", REALM, "x"));
+        assert_eq!(
+            contents.parse::<DigestDB>().unwrap(),
+            contents.as_bytes().load_htdigest().unwrap()
+        );
+
+        let mut latin1 = b"m\xfcller:".to_vec();
+        latin1.extend(format!("{}:{}\n", REALM, digest_ha1("x", REALM, "x")).as_bytes());
+        let db = (&latin1[..]).load_htdigest().unwrap();
+        assert!(db.contains_user("m\u{fc}ller", REALM));
+    }
+
+    #[test]
+    fn header_parsing() {
+        let credentials = DigestCredentials::parse(
+            r#"digest username="a \"quoted\" name",realm="r",nonce=n,uri="/",response="x""#,
+        )
+        .unwrap();
+        assert_eq!("a \"quoted\" name", credentials.username);
+        assert_eq!("n", credentials.nonce);
+        assert_eq!(None, credentials.qop);
+
+        assert_eq!(None, DigestCredentials::parse("Basic YXNmOm9pbms="));
+        assert_eq!(
+            None,
+            DigestCredentials::parse(r#"Digest username="asf", realm="r""#)
+        );
+        assert_eq!(
+            None,
+            DigestCredentials::parse(r#"Digest username="unterminated"#)
+        );
+    }
+
+    #[test]
+    fn verify() {
+        let db = db();
+        let nonces = Nonces::new(Duration::from_secs(60));
+        let nonce = nonces.issue();
+        let verify =
+            |credentials: &DigestCredentials| db.verify(credentials, REALM, "GET", &nonces);
+
+        assert_eq!(
+            Ok(()),
+            verify(&credentials(&nonce, "00000001", "Circle Of Life"))
+        );
+        // replayed:
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword)),
+            verify(&credentials(&nonce, "00000001", "Circle Of Life"))
+        );
+        assert_eq!(
+            Ok(()),
+            verify(&credentials(&nonce, "00000002", "Circle Of Life"))
+        );
+
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword)),
+            verify(&credentials(&nonce, "00000003", "Square Of Life"))
+        );
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::NoSuchUser)),
+            db.verify(
+                &credentials(&nonce, "00000003", "Circle Of Life"),
+                "otherrealm",
+                "GET",
+                &nonces
+            )
+        );
+        // signed for a different method:
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword)),
+            db.verify(
+                &credentials(&nonce, "00000003", "Circle Of Life"),
+                REALM,
+                "POST",
+                &nonces
+            )
+        );
+
+        let forged = Nonces::new(Duration::from_secs(60)).issue();
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword)),
+            verify(&credentials(&forged, "00000001", "Circle Of Life"))
+        );
+    }
+
+    #[test]
+    fn stale_nonces() {
+        let db = db();
+        let nonces = Nonces::new(Duration::from_secs(0));
+        let credentials = credentials(&nonces.issue(), "00000001", "Circle Of Life");
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::StaleNonce)),
+            db.verify(&credentials, REALM, "GET", &nonces)
+        );
+
+        let challenge = nonces.challenge("Private \"area\"", true);
+        assert!(challenge.starts_with("Digest realm=\"Private \\\"area\\\"\", qop=\"auth\""));
+        assert!(challenge.ends_with(", stale=true"));
+    }
+}
@@ -1,5 +1,6 @@
 use std::error;
 use std::fmt;
+use std::time::Duration;
 
 /// Authentication failure.
 #[derive(Debug)]
@@ -11,6 +12,18 @@ pub enum AuthError {
     /// Indicates a faulty password hash value or failure to hash the
     /// provided credentials.
     StorageError(bcrypt::BcryptError),
+
+    /// Credentials weren't validated because too many validations
+    /// are already waiting for their turn.
+    Overloaded,
+
+    /// Credentials weren't validated because there were too many
+    /// failed attempts for the user or from the client recently. The
+    /// duration indicates when the next attempt will be considered.
+    Throttled(Duration),
+
+    /// The credentials couldn't be decoded from an HTTP header.
+    MalformedHeader(MalformedHeader),
 }
 
 impl PartialEq for AuthError {
@@ -23,6 +36,10 @@ impl PartialEq for AuthError {
             // storage errors are the same.
             (StorageError(_), StorageError(_)) => true,
 
+            (Overloaded, Overloaded) => true,
+            (Throttled(l), Throttled(r)) => l == r,
+            (MalformedHeader(l), MalformedHeader(r)) => l == r,
+
             (_, _) => false,
         }
     }
@@ -39,6 +56,7 @@ impl error::Error for AuthError {
         use AuthError::*;
         match self {
             StorageError(err) => Some(err),
+            MalformedHeader(err) => Some(err),
             _ => None,
         }
     }
@@ -56,6 +74,44 @@ macro_rules! impl_from_error {
 
 impl_from_error!(bcrypt::BcryptError, AuthError::StorageError);
 impl_from_error!(BadCredentials, AuthError::NotAuthenticated);
+impl_from_error!(MalformedHeader, AuthError::MalformedHeader);
+
+/// The ways in which the value of an `Authorization` header can fail
+/// to hold HTTP Basic credentials.
+#[derive(Debug, PartialEq)]
+pub enum MalformedHeader {
+    /// The credentials use a scheme other than `Basic`.
+    NotBasic,
+
+    /// The `Basic` scheme isn't followed by any credentials.
+    Empty,
+
+    /// The credentials aren't valid base64.
+    BadBase64,
+
+    /// The decoded credentials have no `:` separating the user name
+    /// from the password.
+    MissingColon,
+
+    /// The user name or password can't be decoded in the expected
+    /// charset.
+    BadCharset,
+}
+
+impl fmt::Display for MalformedHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        use MalformedHeader::*;
+        match self {
+            NotBasic => write!(f, "not HTTP Basic credentials"),
+            Empty => write!(f, "credentials are missing"),
+            BadBase64 => write!(f, "credentials are not valid base64"),
+            MissingColon => write!(f, "credentials lack a colon after the user name"),
+            BadCharset => write!(f, "credentials are not encoded in the expected charset"),
+        }
+    }
+}
+
+impl error::Error for MalformedHeader {}
 
 /// All the things that could go wrong when checking credentials
 /// against password storage.
@@ -78,6 +134,14 @@ pub enum BadCredentials {
     /// User exists but their password is stored in an insecure way,
     /// and won't be validated.
     InsecureStorage,
+
+    /// The password is longer than bcrypt can take into account, and
+    /// the `LongPasswordPolicy` in effect rejects it.
+    PasswordTooLong,
+
+    /// The HTTP Digest response is correct, but was computed with a
+    /// nonce that has expired; the client should retry with a new one.
+    StaleNonce,
 }
 
 impl fmt::Display for BadCredentials {
@@ -89,3 +153,44 @@ impl fmt::Display for BadCredentials {
 }
 
 impl<'a> error::Error for BadCredentials {}
+
+/// Things that can go wrong when changing a user's entry in a
+/// `PasswordDB`.
+#[derive(Debug, PartialEq)]
+pub enum UpdateError {
+    /// The user name is empty or contains a character that can't be
+    /// stored in a `.htpasswd` file (`:` or a line break, or for files
+    /// read as Latin-1, one that Latin-1 can't encode).
+    BadUsername,
+
+    /// The new password could not be hashed.
+    Hash(AuthError),
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        use UpdateError::*;
+        match self {
+            BadUsername => write!(f, "user name can't be stored in a .htpasswd file"),
+            Hash(AuthError::NotAuthenticated(BadCredentials::PasswordTooLong)) => {
+                write!(f, "password is too long for bcrypt")
+            }
+            Hash(_) => write!(f, "could not hash password"),
+        }
+    }
+}
+
+impl error::Error for UpdateError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            UpdateError::Hash(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<AuthError> for UpdateError {
+    fn from(f: AuthError) -> Self {
+        UpdateError::Hash(f)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::basic::latin1_decode;
+use crate::parse::parse_groups;
+use crate::{LoadFailure, ParseFailure};
+use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::ops::Deref;
+use std::path::Path;
+use std::str::{self, FromStr};
+use std::sync::Arc;
+
+/// The group memberships from an Apache `.htgroup` file (as used with
+/// `AuthGroupFile`), in which each line names a group followed by its
+/// members:
+///
+/// ```text
+/// admins: asf bsf
+/// staff: asf csf
+/// ```
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct GroupDB {
+    groups: HashMap<String, HashSet<String>>,
+}
+
+impl GroupDB {
+    /// Returns whether `user` is a member of `group`.
+    pub fn is_member(&self, user: &str, group: &str) -> bool {
+        self.groups
+            .get(group)
+            .map_or(false, |members| members.contains(user))
+    }
+
+    /// Returns the names of all the groups that `user` is a member
+    /// of, in alphabetical order.
+    pub fn groups_of(&self, user: &str) -> Vec<&str> {
+        let mut groups: Vec<&str> = self
+            .groups
+            .iter()
+            .filter(|(_, members)| members.contains(user))
+            .map(|(group, _)| group.as_str())
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    /// Returns the names of all groups, in no particular order.
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.groups.keys().map(String::as_str)
+    }
+}
+
+impl FromStr for GroupDB {
+    type Err = ParseFailure;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_htgroup_str(s)
+    }
+}
+
+/// Parses an htgroup-formatted string. Blank lines and lines starting
+/// with `#` are ignored, as they are by Apache.
+pub fn parse_htgroup_str(contents: &str) -> Result<GroupDB, ParseFailure> {
+    let groups = parse_groups(contents)?
+        .into_iter()
+        .map(|(group, members)| (group, members.into_iter().collect()))
+        .collect();
+    Ok(GroupDB { groups })
+}
+
+/// Like `parse_htgroup_str`, but for the contents of a file, which
+/// (like `parse_htpasswd_bytes`) are read as ISO-8859-1 (Latin-1) if
+/// they aren't valid UTF-8, so that the members' names match those
+/// from a password file in the same charset.
+pub fn parse_htgroup_bytes(contents: &[u8]) -> Result<GroupDB, ParseFailure> {
+    match str::from_utf8(contents) {
+        Ok(contents) => parse_htgroup_str(contents),
+        Err(_) => parse_htgroup_str(&latin1_decode(contents)),
+    }
+}
+
+/// Allows loading .htgroup data from the same types as
+/// `HtpasswdLoad`.
+pub trait HtgroupLoad {
+    /// Reads self to the end and parses a .htgroup database from it.
+    fn load_htgroup(&mut self) -> Result<GroupDB, LoadFailure>;
+}
+
+impl<T> HtgroupLoad for T
+where
+    T: Read + Sized,
+{
+    fn load_htgroup(&mut self) -> Result<GroupDB, LoadFailure> {
+        let mut contents = vec![];
+        self.read_to_end(&mut contents)?;
+        Ok(parse_htgroup_bytes(&contents)?)
+    }
+}
+
+impl HtgroupLoad for Path {
+    fn load_htgroup(&mut self) -> Result<GroupDB, LoadFailure> {
+        let contents = fs::read(self)?;
+        Ok(parse_htgroup_bytes(&contents)?)
+    }
+}
+
+/// Keeps a `GroupDB` loaded and updated in memory, like
+/// `PasswordDBSource` does for password databases.
+pub trait GroupDBSource {
+    /// Any error that can occur from attempts to load the `GroupDB`.
+    /// This will typically be `LoadFailure`.
+    type Error: Sized + Error;
+
+    /// The type returned by `get`, e.g. a lock guard.
+    type Reference: Sized + Deref<Target = Result<GroupDB, Self::Error>>;
+
+    /// Return a RAII object that yields the most recently loaded
+    /// `GroupDB`, or the error that the last load attempt resulted in.
+    fn get(&self) -> Self::Reference;
+}
+
+/// A DB that was loaded once and is never refreshed.
+impl<E: Error> GroupDBSource for Arc<Result<GroupDB, E>> {
+    type Error = E;
+    type Reference = Self;
+
+    fn get(&self) -> Self {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ParseErrorKind;
+
+    const GROUPS: &str = "admins: asf bsf
+staff: asf csf
+";
+
+    #[test]
+    fn membership() {
+        let db: GroupDB = GROUPS.parse().unwrap();
+        assert!(db.is_member("asf", "admins"));
+        assert!(db.is_member("csf", "staff"));
+        assert!(!db.is_member("csf", "admins"));
+        assert!(!db.is_member("asf", "nobody"));
+
+        assert_eq!(vec!["admins", "staff"], db.groups_of("asf"));
+        assert_eq!(vec!["staff"], db.groups_of("csf"));
+        assert!(db.groups_of("dsf").is_empty());
+
+        let mut groups: Vec<&str> = db.groups().collect();
+        groups.sort();
+        assert_eq!(vec!["admins", "staff"], groups);
+    }
+
+    #[test]
+    fn load() {
+        assert_eq!(
+            GROUPS.parse::<GroupDB>().unwrap(),
+            GROUPS.as_bytes().load_htgroup().unwrap()
+        );
+        match "admins asf".as_bytes().load_htgroup() {
+            Err(LoadFailure::Parse(f)) => assert_eq!(ParseErrorKind::BadGroupName, f.kind),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn latin1() {
+        let db = (&b"admins: m\xfcller asf\n"[..]).load_htgroup().unwrap();
+        assert!(db.is_member("m\u{fc}ller", "admins"));
+        assert!(db.is_member("asf", "admins"));
+    }
+}
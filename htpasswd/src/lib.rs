@@ -13,6 +13,10 @@
 //! SHA1, crypt or plaintext) will result in an authentication error
 //! indicating that the storage format is insecure.
 //!
+//! Group memberships can be loaded from Apache `.htgroup` files into
+//! a `GroupDB`, and credentials for HTTP Digest authentication from
+//! `htdigest` files into a `DigestDB`.
+//!
 //! # Example
 //!
 //! ```rust
@@ -27,26 +31,56 @@
 //!
 
 use bcrypt;
+use indexmap::IndexMap;
 use nom;
+use std::borrow::Cow;
 use std::collections::hash_map::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::fs::read_to_string;
+use std::fs;
 use std::io;
 use std::io::Read;
 use std::ops::Deref;
 use std::path::Path;
 use std::str;
 use std::str::FromStr;
+use std::sync::Arc;
 
 // The type to use as input to parsers in this crate.
 pub use nom::types::CompleteStr as Input;
 
+mod audit;
+mod basic;
+mod cache;
+mod check;
+mod digest;
 mod errors;
+mod group;
 mod parse;
+#[cfg(feature = "pool")]
+mod pool;
+mod save;
+mod server;
+mod throttle;
 
+pub use audit::{audit_file, AuditPolicy, AuditReport, Finding, Problem, Scheme, Severity};
+pub use basic::{decode_basic_header, Charset};
+pub use cache::CredentialCache;
+pub use check::{check_htpasswd_bytes, check_htpasswd_str, Diagnostic, DiagnosticKind};
+pub use digest::{
+    digest_ha1, parse_htdigest_bytes, parse_htdigest_str, DigestCredentials, DigestDB,
+    DigestDBSource, HtdigestLoad, Nonces,
+};
 pub use errors::*;
+pub use group::{parse_htgroup_bytes, parse_htgroup_str, GroupDB, GroupDBSource, HtgroupLoad};
 pub use parse::{ParseErrorKind, ParseFailure};
+#[cfg(feature = "pool")]
+pub use pool::{ValidationFuture, ValidationPool};
+pub use save::{save_htpasswd, LockedFile};
+pub use server::{
+    basic_challenge, checked_realm, is_valid_realm, AuthenticatedUser, DEFAULT_REALM,
+};
+pub use throttle::Throttle;
 
 /// Represents a password hashed with a particular method.
 #[derive(Debug, PartialEq)]
@@ -57,27 +91,328 @@ enum PasswordHash {
     Crypt(String),
 }
 
+impl fmt::Display for PasswordHash {
+    /// Renders the hash the way it is stored in a `.htpasswd` file.
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        use crate::PasswordHash::*;
+        match self {
+            Bcrypt(hash) | Crypt(hash) => write!(f, "{}", hash),
+            SHA1(hash) => write!(f, "{{SHA}}{}", hash),
+            MD5(hash) => write!(f, "$apr1${}", hash),
+        }
+    }
+}
+
 /// An in-memory representation of a `.htpasswd` file.
 #[derive(Debug, PartialEq)]
-pub struct PasswordDB(HashMap<String, PasswordHash>);
+pub struct PasswordDB {
+    /// The entries, in the order of the file they were loaded from.
+    entries: IndexMap<String, PasswordHash>,
+
+    /// Users that had more than one entry in the parsed file; only
+    /// the last one is kept in `entries`.
+    duplicates: Vec<String>,
+
+    /// A hash representative of the ones in `entries`, used to make
+    /// failed lookups take as long as failed password checks.
+    dummy_hash: Option<String>,
+    equalize_timing: bool,
+
+    long_passwords: LongPasswordPolicy,
+
+    /// The file wasn't UTF-8 and was read as Latin-1, so passwords
+    /// are checked in Latin-1 as well.
+    latin1: bool,
+}
+
+/// The number of password bytes that bcrypt takes into account; any
+/// bytes past this limit don't influence the resulting hash.
+pub const BCRYPT_MAX_PASSWORD_LEN: usize = 72;
+
+/// Determines how passwords longer than `BCRYPT_MAX_PASSWORD_LEN`
+/// bytes are treated, both when validating and when hashing them.
+///
+/// bcrypt silently ignores everything past the 72nd byte of a
+/// password, so with a truncating policy, any password that shares
+/// its first 72 bytes with the correct one is accepted as well.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LongPasswordPolicy {
+    /// Refuse over-long passwords with
+    /// `BadCredentials::PasswordTooLong`. This is the default.
+    Reject,
+
+    /// Consider only the first 72 bytes of a password. This is what
+    /// Apache's bcrypt implementation does, so it is the policy to
+    /// pick when `.htpasswd` files are shared with Apache and its
+    /// users have set long passwords already.
+    Truncate,
+}
+
+impl Default for LongPasswordPolicy {
+    fn default() -> Self {
+        LongPasswordPolicy::Reject
+    }
+}
+
+impl LongPasswordPolicy {
+    /// Returns the part of the password that bcrypt should be given,
+    /// or an error if the password is not acceptable.
+    pub fn apply(self, password: &str) -> Result<&[u8], BadCredentials> {
+        self.apply_bytes(password.as_bytes())
+    }
+
+    pub(crate) fn apply_bytes(self, bytes: &[u8]) -> Result<&[u8], BadCredentials> {
+        match self {
+            _ if bytes.len() <= BCRYPT_MAX_PASSWORD_LEN => Ok(bytes),
+            LongPasswordPolicy::Reject => Err(BadCredentials::PasswordTooLong),
+            LongPasswordPolicy::Truncate => Ok(&bytes[..BCRYPT_MAX_PASSWORD_LEN]),
+        }
+    }
+}
 
 impl PasswordDB {
+    fn new(parsed: Vec<(String, PasswordHash)>) -> PasswordDB {
+        let mut entries = IndexMap::new();
+        let mut duplicates = vec![];
+        for (user, hash) in parsed {
+            if let Some(_previous) = entries.insert(user.clone(), hash) {
+                duplicates.push(user);
+            }
+        }
+        duplicates.sort();
+        duplicates.dedup();
+        let dummy_hash = typical_bcrypt_hash(&entries);
+        PasswordDB {
+            entries,
+            duplicates,
+            dummy_hash,
+            equalize_timing: true,
+            long_passwords: LongPasswordPolicy::default(),
+            latin1: false,
+        }
+    }
+
     /// Checks the provided username and password against the database
     /// and returns `Ok(())` if both match. Otherwise, returns an
     /// error indicating the problem with the provided or the stored
     /// credentials.
+    ///
+    /// Unless disabled with `set_equalize_timing`, credentials for
+    /// users that don't exist (or whose password is stored insecurely)
+    /// are still checked against a bcrypt hash of the cost most
+    /// commonly used in the database, so that the time it takes to
+    /// reject them doesn't reveal which user names are valid.
+    ///
+    /// Passwords longer than `BCRYPT_MAX_PASSWORD_LEN` bytes are
+    /// handled according to the database's `LongPasswordPolicy`.
     pub fn validate(&self, user: &str, password: &str) -> Result<(), AuthError> {
+        self.prepare(user, password)?.run()
+    }
+
+    /// Looks up everything needed to validate the credentials, so
+    /// that the expensive part can happen without access to the
+    /// database, e.g. on another thread.
+    pub(crate) fn prepare(&self, user: &str, password: &str) -> Result<Verification, AuthError> {
         use crate::PasswordHash::*;
-        match self.0.get(user).ok_or_else(|| BadCredentials::NoSuchUser)? {
-            Bcrypt(hash) => match bcrypt::verify(password, hash)? {
+        let password = self.encode_password(password)?;
+        let password = self.long_passwords.apply_bytes(&password)?.to_vec();
+        Ok(match self.entries.get(user) {
+            Some(Bcrypt(hash)) => Verification::Check {
+                password,
+                hash: hash.clone(),
+            },
+            Some(_) => Verification::Reject {
+                password,
+                dummy_hash: self.dummy_hash(),
+                reason: BadCredentials::InsecureStorage,
+            },
+            None => Verification::Reject {
+                password,
+                dummy_hash: self.dummy_hash(),
+                reason: BadCredentials::NoSuchUser,
+            },
+        })
+    }
+
+    /// Returns the bytes that bcrypt hashed for `password`: its UTF-8
+    /// encoding, or for files read as Latin-1, its Latin-1 encoding.
+    fn encode_password<'a>(&self, password: &'a str) -> Result<Cow<'a, [u8]>, BadCredentials> {
+        if !self.latin1 {
+            return Ok(Cow::Borrowed(password.as_bytes()));
+        }
+        basic::latin1_encode(password)
+            .map(Cow::Owned)
+            .ok_or(BadCredentials::InvalidPassword)
+    }
+
+    pub(crate) fn is_latin1(&self) -> bool {
+        self.latin1
+    }
+
+    /// Enables or disables the timing equalization described on
+    /// `validate`. It is enabled by default.
+    pub fn set_equalize_timing(&mut self, enabled: bool) {
+        self.equalize_timing = enabled;
+    }
+
+    /// Sets the policy for passwords that exceed bcrypt's length
+    /// limit. The default is `LongPasswordPolicy::Reject`.
+    pub fn set_long_password_policy(&mut self, policy: LongPasswordPolicy) {
+        self.long_passwords = policy;
+    }
+
+    pub(crate) fn long_password_policy(&self) -> LongPasswordPolicy {
+        self.long_passwords
+    }
+
+    /// Hashes a new password with bcrypt at the given cost, subject
+    /// to the database's `LongPasswordPolicy`. Like `validate`, it
+    /// hashes the password's Latin-1 encoding for files read as
+    /// Latin-1, refusing passwords that Latin-1 can't encode with
+    /// `BadCredentials::InvalidPassword`.
+    pub fn hash_password(&self, password: &str, cost: u32) -> Result<String, AuthError> {
+        let encoded = self.encode_password(password)?;
+        let password = self.long_passwords.apply_bytes(&encoded)?;
+        Ok(bcrypt::hash(password, cost)?)
+    }
+
+    /// Returns true if the database has an entry for `user`.
+    pub fn contains_user(&self, user: &str) -> bool {
+        self.entries.contains_key(user)
+    }
+
+    /// Hashes `password` with bcrypt at the given cost and stores it
+    /// as the password for `user`, adding the user if necessary.
+    pub fn set_password(
+        &mut self,
+        user: &str,
+        password: &str,
+        cost: u32,
+    ) -> Result<(), UpdateError> {
+        if user.is_empty() || user.contains(|c| c == ':' || c == '\n' || c == '\r') {
+            return Err(UpdateError::BadUsername);
+        }
+        if self.latin1 && basic::latin1_encode(user).is_none() {
+            return Err(UpdateError::BadUsername);
+        }
+        let hash = self.hash_password(password, cost)?;
+        self.entries
+            .insert(user.to_string(), PasswordHash::Bcrypt(hash));
+        self.duplicates.retain(|dup| dup != user);
+        self.dummy_hash = typical_bcrypt_hash(&self.entries);
+        Ok(())
+    }
+
+    /// Removes `user` from the database, returning true if they
+    /// existed.
+    pub fn remove(&mut self, user: &str) -> bool {
+        let removed = self.entries.shift_remove(user).is_some();
+        self.duplicates.retain(|dup| dup != user);
+        self.dummy_hash = typical_bcrypt_hash(&self.entries);
+        removed
+    }
+
+    /// Renders the database like `Display`, encoded in the charset of
+    /// the file it was read from: Latin-1 for files that
+    /// `parse_htpasswd_bytes` read as such, and UTF-8 otherwise.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let contents = self.to_string();
+        if !self.latin1 {
+            return contents.into_bytes();
+        }
+        basic::latin1_encode(&contents)
+            .expect("set_password only adds users whose names Latin-1 can encode")
+    }
+
+    /// Returns the names of all users in the database, in the order
+    /// they were loaded or added in.
+    pub fn users(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// Returns the stored bcrypt hash for a user, if there is one.
+    pub(crate) fn bcrypt_hash(&self, user: &str) -> Option<&str> {
+        match self.entries.get(user) {
+            Some(PasswordHash::Bcrypt(hash)) => Some(hash),
+            _ => None,
+        }
+    }
+
+    fn dummy_hash(&self) -> Option<String> {
+        if self.equalize_timing {
+            self.dummy_hash.clone()
+        } else {
+            None
+        }
+    }
+}
+
+/// The outcome of looking up a user in a `PasswordDB`, with the bcrypt
+/// work that remains to be done to validate their password.
+pub(crate) enum Verification {
+    /// The password needs to be checked against the user's hash.
+    Check { password: Vec<u8>, hash: String },
+
+    /// The credentials are invalid, but checking the password against
+    /// the dummy hash (if any) keeps that from being obvious.
+    Reject {
+        password: Vec<u8>,
+        dummy_hash: Option<String>,
+        reason: BadCredentials,
+    },
+}
+
+impl Verification {
+    pub(crate) fn run(self) -> Result<(), AuthError> {
+        match self {
+            Verification::Check { password, hash } => match bcrypt::verify(&password, &hash)? {
                 true => Ok(()),
                 false => Err(BadCredentials::InvalidPassword)?,
             },
-            _ => Err(BadCredentials::InsecureStorage)?,
+            Verification::Reject {
+                password,
+                dummy_hash,
+                reason,
+            } => {
+                if let Some(hash) = dummy_hash {
+                    // The result doesn't matter, only the time spent on it:
+                    let _ = bcrypt::verify(&password, &hash);
+                }
+                Err(reason)?
+            }
         }
     }
 }
 
+/// Returns the cost parameter of a bcrypt hash like `$2y$05$...`.
+fn bcrypt_cost(hash: &str) -> Option<u32> {
+    hash.get(4..6)?.parse().ok()
+}
+
+/// Picks a bcrypt hash of the cost most commonly used in
+/// `entries`. Ties are resolved in favor of the higher cost.
+fn typical_bcrypt_hash(entries: &IndexMap<String, PasswordHash>) -> Option<String> {
+    let mut by_cost: HashMap<u32, (usize, &str)> = HashMap::new();
+    for hash in entries.values() {
+        if let PasswordHash::Bcrypt(hash) = hash {
+            if let Some(cost) = bcrypt_cost(hash) {
+                by_cost.entry(cost).or_insert((0, hash.as_str())).0 += 1;
+            }
+        }
+    }
+    by_cost
+        .into_iter()
+        .max_by_key(|(cost, (count, _))| (*count, *cost))
+        .map(|(_, (_, hash))| hash.to_string())
+}
+
+impl Default for PasswordDB {
+    /// Returns a database without any users.
+    fn default() -> Self {
+        PasswordDB::new(vec![])
+    }
+}
+
 impl FromStr for PasswordDB {
     type Err = ParseFailure;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -85,11 +420,58 @@ impl FromStr for PasswordDB {
     }
 }
 
+impl fmt::Display for PasswordDB {
+    /// Renders the database in `.htpasswd` format, one line per user,
+    /// in the order they were loaded or added in.
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for (user, hash) in &self.entries {
+            writeln!(f, "{}:{}", user, hash)?;
+        }
+        Ok(())
+    }
+}
+
 /// Parses an htpasswd-formatted string and returns the entries in it
-/// as a hash table, mapping user names to password hashes.
+/// as a hash table, mapping user names to password hashes. The last
+/// entry must not be followed by a line break; the contents of files
+/// are best loaded with `HtpasswdLoad`.
 pub fn parse_htpasswd_str(contents: &str) -> Result<PasswordDB, ParseFailure> {
     let entries = parse::parse_entries(contents)?;
-    Ok(PasswordDB(entries))
+    Ok(PasswordDB::new(entries))
+}
+
+/// Like `parse_htpasswd_str`, but for the contents of a file, whose
+/// lines may all end in line breaks, and which may have been written
+/// in a legacy charset: if `contents` isn't valid UTF-8, it is read as
+/// ISO-8859-1 (Latin-1), and passwords are checked against the hashes
+/// in Latin-1 too, as the tools that wrote such files hashed them.
+/// Rendering the database with `Display` produces UTF-8; `to_bytes`
+/// renders it in the charset it was read in.
+pub fn parse_htpasswd_bytes(contents: &[u8]) -> Result<PasswordDB, ParseFailure> {
+    match str::from_utf8(contents) {
+        Ok(contents) => Ok(PasswordDB::new(parse::parse_file_entries(contents)?)),
+        Err(_) => {
+            let contents = basic::latin1_decode(contents);
+            let mut db = PasswordDB::new(parse::parse_file_entries(&contents)?);
+            db.latin1 = true;
+            Ok(db)
+        }
+    }
+}
+
+/// Like `parse_htpasswd_str`, but instead of stopping at the first
+/// line that fails to parse, returns the failures for all of them.
+pub fn parse_htpasswd_str_all(contents: &str) -> Result<PasswordDB, Vec<ParseFailure>> {
+    let (parsed, failures) = parse::parse_lines(contents);
+    if !failures.is_empty() {
+        return Err(failures);
+    }
+    Ok(PasswordDB::new(
+        parsed
+            .into_iter()
+            .map(|entry| (entry.user, entry.hash))
+            .collect(),
+    ))
 }
 
 #[derive(Debug)]
@@ -138,16 +520,16 @@ where
     T: Read + Sized,
 {
     fn load_htpasswd(&mut self) -> Result<PasswordDB, LoadFailure> {
-        let mut str = String::new();
-        self.read_to_string(&mut str)?;
-        Ok(parse_htpasswd_str(&str)?)
+        let mut contents = vec![];
+        self.read_to_end(&mut contents)?;
+        Ok(parse_htpasswd_bytes(&contents)?)
     }
 }
 
 impl HtpasswdLoad for Path {
     fn load_htpasswd(&mut self) -> Result<PasswordDB, LoadFailure> {
-        let contents = read_to_string(self)?;
-        Ok(parse_htpasswd_str(&contents)?)
+        let contents = fs::read(self)?;
+        Ok(parse_htpasswd_bytes(&contents)?)
     }
 }
 
@@ -179,6 +561,16 @@ pub trait PasswordDBSource {
     fn get(&self) -> Self::Reference;
 }
 
+/// A DB that was loaded once and is never refreshed.
+impl<E: Error> PasswordDBSource for Arc<Result<PasswordDB, E>> {
+    type Error = E;
+    type Reference = Self;
+
+    fn get(&self) -> Self {
+        self.clone()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -234,6 +626,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn all_bad_fields() {
+        assert_eq!(
+            Err(vec![
+                ParseFailure {
+                    kind: ParseErrorKind::BadPassword,
+                    offset: 69,
+                    line: 2,
+                    column: 5
+                },
+                ParseFailure {
+                    kind: ParseErrorKind::BadUsername,
+                    offset: 70,
+                    line: 3,
+                    column: 1
+                }
+            ]),
+            parse_htpasswd_str_all(
+                "asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96\n___:\n___"
+            )
+        );
+        let contents = "asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96";
+        assert_eq!(
+            parse_htpasswd_str(contents).ok(),
+            parse_htpasswd_str_all(contents).ok()
+        );
+    }
+
     #[test]
     fn validate() {
         let entries = parse_htpasswd_str(
@@ -252,4 +672,126 @@ bsf:$2y$05$9U5xoWYrBX687.C.MEhsae5LfOrlUqqMSfE2Cpo4K.jyvy3lA.Ijy",
             entries.validate("unperson", "unpassword")
         );
     }
+
+    /// Returns the hash that a password would be checked against,
+    /// and the reason it would be rejected regardless.
+    fn checked_against(
+        entries: &PasswordDB,
+        user: &str,
+    ) -> (Option<String>, Option<BadCredentials>) {
+        match entries.prepare(user, "wrong").unwrap() {
+            Verification::Check { hash, .. } => (Some(hash), None),
+            Verification::Reject {
+                dummy_hash, reason, ..
+            } => (dummy_hash, Some(reason)),
+        }
+    }
+
+    #[test]
+    fn unknown_users_take_as_long_as_known_ones() {
+        let asf = "$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96";
+        let mut entries = parse_htpasswd_str(&format!(
+            "asf:{}
+bsf:$2y$04$9U5xoWYrBX687.C.MEhsae5LfOrlUqqMSfE2Cpo4K.jyvy3lA.Ijy
+csf:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=
+dsf:{}",
+            asf, asf
+        ))
+        .unwrap();
+        let dummy = Some(asf.to_string());
+        assert_eq!(dummy, entries.dummy_hash);
+
+        assert_eq!((dummy.clone(), None), checked_against(&entries, "asf"));
+        assert_eq!(
+            (dummy.clone(), Some(BadCredentials::NoSuchUser)),
+            checked_against(&entries, "unperson")
+        );
+        assert_eq!(
+            (dummy, Some(BadCredentials::InsecureStorage)),
+            checked_against(&entries, "csf")
+        );
+
+        entries.set_equalize_timing(false);
+        assert_eq!(
+            (None, Some(BadCredentials::NoSuchUser)),
+            checked_against(&entries, "unperson")
+        );
+    }
+
+    #[test]
+    fn long_passwords() {
+        let prefix = "x".repeat(BCRYPT_MAX_PASSWORD_LEN);
+        let long = format!("{}anything", prefix);
+        let hash = bcrypt::hash(prefix.as_bytes(), 4).unwrap();
+        let mut entries = parse_htpasswd_str(&format!("asf:{}", hash)).unwrap();
+
+        assert_eq!(Ok(()), entries.validate("asf", &prefix));
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::PasswordTooLong)),
+            entries.validate("asf", &long)
+        );
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::PasswordTooLong)),
+            entries.hash_password(&long, 4).map(|_| ())
+        );
+
+        entries.set_long_password_policy(LongPasswordPolicy::Truncate);
+        assert_eq!(Ok(()), entries.validate("asf", &long));
+        let hash = entries.hash_password(&long, 4).unwrap();
+        assert_eq!(Ok(true), bcrypt::verify(&prefix, &hash).map_err(|_| ()));
+    }
+
+    #[test]
+    fn round_trip() {
+        let contents = "dsf:rqXexS6ZhobKA
+asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96
+csf:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/
+bsf:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=
+";
+        let mut entries = contents.as_bytes().load_htpasswd().unwrap();
+        assert_eq!(contents, entries.to_string());
+        assert_eq!(
+            vec!["dsf", "asf", "csf", "bsf"],
+            entries.users().collect::<Vec<_>>()
+        );
+
+        assert!(entries.remove("asf"));
+        assert_eq!(Ok(()), entries.set_password("asf", "oink", 4));
+        assert_eq!(
+            vec!["dsf", "csf", "bsf", "asf"],
+            entries.users().collect::<Vec<_>>()
+        );
+        assert_eq!("", PasswordDB::default().to_string());
+    }
+
+    #[test]
+    fn updates() {
+        let mut entries = PasswordDB::default();
+        assert_eq!(Ok(()), entries.set_password("asf", "oink", 4));
+        assert_eq!(Ok(()), entries.validate("asf", "oink"));
+        assert_eq!(
+            Some(4),
+            entries.dummy_hash.as_ref().and_then(|h| bcrypt_cost(h))
+        );
+        assert_eq!(Ok(()), entries.set_password("asf", "moo", 4));
+        assert_eq!(Ok(()), entries.validate("asf", "moo"));
+
+        assert_eq!(
+            Err(UpdateError::BadUsername),
+            entries.set_password("a:sf", "oink", 4)
+        );
+        assert_eq!(
+            Err(UpdateError::BadUsername),
+            entries.set_password("asf\nbsf", "oink", 4)
+        );
+        assert_eq!(
+            Err(UpdateError::BadUsername),
+            entries.set_password("", "oink", 4)
+        );
+
+        assert!(entries.remove("asf"));
+        assert!(!entries.remove("asf"));
+        assert!(!entries.contains_user("asf"));
+        assert_eq!(None, entries.dummy_hash);
+    }
 }
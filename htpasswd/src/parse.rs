@@ -22,6 +22,14 @@ pub enum ParseErrorKind {
 
     BrokenHtpasswd,
 
+    /// Indicates that the group name on a `.htgroup` line failed to
+    /// parse.
+    BadGroupName,
+
+    /// Indicates that the realm field on an `htdigest` line failed to
+    /// parse.
+    BadRealm,
+
     /// An unexpected parse error, indicates a bug in the htpasswd crate
     Unknown,
 }
@@ -43,6 +51,8 @@ impl fmt::Display for ParseErrorKind {
                 BadPassword => "badly-formatted password field",
                 GarbageAtEnd => "last line in file is not recognized",
                 BrokenHtpasswd => ".htpasswd didn't parse",
+                BadGroupName => "badly-formatted group name field (forgot a `:`?)",
+                BadRealm => "badly-formatted realm field (forgot a `:`?)",
                 Unknown => "bug in htpasswd crate",
             }
         )
@@ -126,6 +136,38 @@ named!(entries<Span, Vec<(UserToken, PasswordHash)>, ParseErrorKind>,
                  (entries))
 );
 
+named!(group_name<Span, String, ParseErrorKind>,
+       return_error!(ErrorKind::Custom(ParseErrorKind::BadGroupName),
+                     fix_error!(ParseErrorKind,
+                                do_parse!(name: terminated!(is_not!(":\r\n"), tag!(":")) >>
+                                          (name.fragment.trim().to_string())))));
+
+named!(group<Span, (String, Vec<String>), ParseErrorKind>,
+       do_parse!(name: group_name >>
+                 members: fix_error!(ParseErrorKind, not_line_ending) >>
+                 ((name, members.fragment.split_whitespace().map(str::to_string).collect())))
+);
+
+named!(realm<Span, String, ParseErrorKind>,
+       return_error!(ErrorKind::Custom(ParseErrorKind::BadRealm),
+                     fix_error!(ParseErrorKind,
+                                do_parse!(realm: terminated!(is_not!(":\r\n"), tag!(":")) >>
+                                          (realm.fragment.to_string())))));
+
+// An htdigest hash is the hex-encoded MD5 of `user:realm:password`:
+named!(digest_hash<Span, String, ParseErrorKind>,
+       return_error!(ErrorKind::Custom(ParseErrorKind::BadPassword),
+                     fix_error!(ParseErrorKind,
+                                do_parse!(hash: verify!(hex_digit, |h: Span| h.fragment.0.len() == 32) >>
+                                          (hash.fragment.to_ascii_lowercase())))));
+
+named!(digest_entry<Span, (UserToken, String, String), ParseErrorKind>,
+       do_parse!(user: user >>
+                 realm: realm >>
+                 hash: digest_hash >>
+                 ((user, realm, hash)))
+);
+
 /// An error indicating something went wrong in parsing a .htaccess file.
 #[derive(Debug, PartialEq)]
 pub struct ParseFailure {
@@ -171,7 +213,7 @@ impl<'a> From<ParseError<'a>> for ParseFailure {
     }
 }
 
-pub(crate) fn parse_entries(input: &str) -> Result<HashMap<String, PasswordHash>, ParseFailure> {
+pub(crate) fn parse_entries(input: &str) -> Result<Vec<(String, PasswordHash)>, ParseFailure> {
     let input = Span::new(CompleteStr::from(input));
     match entries(input) {
         Ok((_rest, entries)) => Ok(entries.into_iter().map(|(ut, pwt)| (ut.0, pwt)).collect()),
@@ -180,6 +222,150 @@ pub(crate) fn parse_entries(input: &str) -> Result<HashMap<String, PasswordHash>
     }
 }
 
+/// Splits a span into lines, each including its line ending.
+struct Lines<'a> {
+    rest: Span<'a>,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Span<'a>;
+
+    fn next(&mut self) -> Option<Span<'a>> {
+        if self.rest.input_len() == 0 {
+            return None;
+        }
+        let line_len = self
+            .rest
+            .fragment
+            .0
+            .find('\n')
+            .map_or(self.rest.input_len(), |i| i + 1);
+        let line = self.rest.slice(..line_len);
+        self.rest = self.rest.slice(line_len..);
+        Some(line)
+    }
+}
+
+fn lines(input: &str) -> Lines {
+    Lines {
+        rest: Span::new(CompleteStr::from(input)),
+    }
+}
+
+fn is_line_ending(rest: &str) -> bool {
+    match rest {
+        "" | "\n" | "\r\n" => true,
+        _ => false,
+    }
+}
+
+fn failure_at(kind: ParseErrorKind, at: Span) -> ParseFailure {
+    ParseFailure {
+        kind,
+        offset: at.offset,
+        line: at.line,
+        column: at.get_column(),
+    }
+}
+
+fn garbage_at_end(rest: Span) -> ParseFailure {
+    failure_at(ParseErrorKind::GarbageAtEnd, rest)
+}
+
+/// Whether a line holds nothing but whitespace or a comment, which
+/// Apache skips in group and digest files.
+fn is_blank_or_comment(line: &Span) -> bool {
+    let content = line.fragment.0.trim_start();
+    content.is_empty() || content.starts_with('#')
+}
+
+/// Parses a `.htgroup` file into a map from group names to their
+/// members. Blank lines and lines starting with `#` are skipped, and
+/// groups that are listed on more than one line get the members from
+/// all of them.
+pub(crate) fn parse_groups(input: &str) -> Result<HashMap<String, Vec<String>>, ParseFailure> {
+    let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+    for line in lines(input).filter(|line| !is_blank_or_comment(line)) {
+        let (rest, (name, members)) = group(line)?;
+        if name.is_empty() {
+            return Err(failure_at(ParseErrorKind::BadGroupName, line));
+        }
+        if !is_line_ending(rest.fragment.0) {
+            return Err(garbage_at_end(rest));
+        }
+        groups.entry(name).or_default().extend(members);
+    }
+    Ok(groups)
+}
+
+/// Parses an `htdigest` file into `(user, realm, hash)` triples.
+pub(crate) fn parse_digest_entries(
+    input: &str,
+) -> Result<Vec<(String, String, String)>, ParseFailure> {
+    let mut entries = vec![];
+    for line in lines(input).filter(|line| !is_blank_or_comment(line)) {
+        let (rest, (user, realm, hash)) = digest_entry(line)?;
+        if !is_line_ending(rest.fragment.0) {
+            return Err(garbage_at_end(rest));
+        }
+        entries.push((user.0, realm, hash));
+    }
+    Ok(entries)
+}
+
+/// An entry parsed by `parse_lines`, with its position in the input.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ParsedLine {
+    pub user: String,
+    pub hash: PasswordHash,
+
+    /// The byte offset of the line's start.
+    pub offset: usize,
+
+    /// The line number.
+    pub line: u32,
+
+    /// Whether the line is terminated by `\r\n` rather than `\n`.
+    pub crlf: bool,
+}
+
+/// Parses the input one line at a time, continuing past lines that
+/// fail to parse, and returns all the entries and failures found.
+pub(crate) fn parse_lines(input: &str) -> (Vec<ParsedLine>, Vec<ParseFailure>) {
+    let mut parsed = vec![];
+    let mut failures = vec![];
+    for line in lines(input) {
+        match entry(line) {
+            Ok((after, (user, hash))) if is_line_ending(after.fragment.0) => {
+                parsed.push(ParsedLine {
+                    user: user.0,
+                    hash,
+                    offset: line.offset,
+                    line: line.line,
+                    crlf: after.fragment.0 == "\r\n",
+                })
+            }
+            Ok((after, _)) => failures.push(garbage_at_end(after)),
+            Result::Err(e) => failures.push(e.into()),
+        }
+    }
+    (parsed, failures)
+}
+
+/// Parses the contents of a `.htpasswd` file, in which (unlike for
+/// `parse_entries`) every line, including the last, may end in a line
+/// break, and which may be empty. Returns the first failure, if any.
+pub(crate) fn parse_file_entries(input: &str) -> Result<Vec<(String, PasswordHash)>, ParseFailure> {
+    let (parsed, failures) = parse_lines(input);
+    match failures.into_iter().next() {
+        Some(failure) => Err(failure),
+        None => Ok(parsed
+            .into_iter()
+            .map(|entry| (entry.user, entry.hash))
+            .collect()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -216,6 +402,168 @@ mod tests {
         );
     }
 
+    #[test]
+    fn final_newline() {
+        assert_eq!(1, parse_entries("asf:$2y$foobar").unwrap().len());
+        assert!(parse_entries("asf:$2y$foobar\n").is_err());
+
+        assert_eq!(1, parse_file_entries("asf:$2y$foobar\n").unwrap().len());
+        assert_eq!(
+            2,
+            parse_file_entries("asf:$2y$foobar\nbsf:$2y$foobar\n")
+                .unwrap()
+                .len()
+        );
+        assert_eq!(1, parse_file_entries("asf:$2y$foobar\r\n").unwrap().len());
+        assert_eq!(1, parse_file_entries("asf:$2y$foobar").unwrap().len());
+        assert_eq!(0, parse_file_entries("").unwrap().len());
+        assert_eq!(
+            Err(ParseFailure {
+                kind: ParseErrorKind::BadUsername,
+                offset: 15,
+                line: 2,
+                column: 1,
+            }),
+            parse_file_entries("asf:$2y$foobar\n\n")
+        );
+    }
+
+    #[test]
+    fn all_lines() {
+        let (parsed, failures) = parse_lines("asf:$2y$foobar\n:oops\nbsf:\r\ncsf:{SHA}foo\r\n\n");
+        assert_eq!(
+            vec![
+                ParsedLine {
+                    user: "asf".to_string(),
+                    hash: PasswordHash::Bcrypt("$2y$foobar".into()),
+                    offset: 0,
+                    line: 1,
+                    crlf: false,
+                },
+                ParsedLine {
+                    user: "csf".to_string(),
+                    hash: PasswordHash::SHA1("foo".into()),
+                    offset: 27,
+                    line: 4,
+                    crlf: true,
+                }
+            ],
+            parsed
+        );
+        assert_eq!(
+            vec![
+                ParseFailure {
+                    kind: ParseErrorKind::BadUsername,
+                    offset: 15,
+                    line: 2,
+                    column: 1,
+                },
+                ParseFailure {
+                    kind: ParseErrorKind::BadPassword,
+                    offset: 25,
+                    line: 3,
+                    column: 5,
+                },
+                ParseFailure {
+                    kind: ParseErrorKind::BadUsername,
+                    offset: 41,
+                    line: 5,
+                    column: 1,
+                }
+            ],
+            failures
+        );
+    }
+
+    #[test]
+    fn groups() {
+        let groups =
+            parse_groups("# comment\nadmins: asf bsf\n\nstaff:asf\t csf \r\nempty:\nadmins: dsf")
+                .unwrap();
+        assert_eq!(
+            vec!["asf", "bsf", "dsf"],
+            groups["admins"]
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["asf", "csf"],
+            groups["staff"]
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+        );
+        assert!(groups["empty"].is_empty());
+
+        assert_eq!(
+            Err(ParseFailure {
+                kind: ParseErrorKind::BadGroupName,
+                offset: 16,
+                line: 2,
+                column: 1,
+            }),
+            parse_groups("admins: asf bsf\nasf bsf\n")
+        );
+        assert_eq!(
+            Err(ParseFailure {
+                kind: ParseErrorKind::BadGroupName,
+                offset: 0,
+                line: 1,
+                column: 1,
+            }),
+            parse_groups(": asf")
+        );
+        assert_eq!(
+            Err(ParseFailure {
+                kind: ParseErrorKind::BadGroupName,
+                offset: 11,
+                line: 2,
+                column: 1,
+            }),
+            parse_groups("admins:asf\n   : x")
+        );
+    }
+
+    #[test]
+    fn digest_entries() {
+        assert_eq!(
+            Ok(vec![(
+                "asf".to_string(),
+                "Private".to_string(),
+                "1f1d1c3e5e1b3bd2a0fba3c46ba8c5b8".to_string()
+            )]),
+            parse_digest_entries("# comment\nasf:Private:1F1D1C3E5E1B3BD2A0FBA3C46BA8C5B8\n")
+        );
+        assert_eq!(
+            Err(ParseFailure {
+                kind: ParseErrorKind::BadRealm,
+                offset: 4,
+                line: 1,
+                column: 5,
+            }),
+            parse_digest_entries("asf:1f1d1c3e5e1b3bd2a0fba3c46ba8c5b8\n")
+        );
+        assert_eq!(
+            Err(ParseFailure {
+                kind: ParseErrorKind::BadPassword,
+                offset: 12,
+                line: 1,
+                column: 13,
+            }),
+            parse_digest_entries("asf:Private:$2y$foobar\n")
+        );
+        assert_eq!(
+            Err(ParseFailure {
+                kind: ParseErrorKind::GarbageAtEnd,
+                offset: 44,
+                line: 1,
+                column: 45,
+            }),
+            parse_digest_entries("asf:Private:1f1d1c3e5e1b3bd2a0fba3c46ba8c5b8 x\n")
+        );
+    }
+
     #[test]
     fn whole_line() {
         let entry = entry(_in("asf:$2y$foobar\n")).unwrap().1;
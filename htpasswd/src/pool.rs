@@ -0,0 +1,183 @@
+use crate::{AuthError, CredentialCache, PasswordDB};
+use futures::{future, Future};
+use futures_cpupool::{Builder, CpuPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A future resolving to the result of validating credentials.
+pub type ValidationFuture = Box<dyn Future<Item = (), Error = AuthError> + Send>;
+
+/// Validates credentials on a dedicated, fixed-size thread pool, so
+/// that the CPU-intensive bcrypt verification doesn't block the
+/// thread that is running an event loop.
+///
+/// The pool's size limits how many cores are busy validating
+/// credentials at any time. Additionally, the number of validations
+/// that may be queued or running can be bounded with
+/// `max_in_flight`: Once that many are outstanding, further
+/// validations fail immediately with `AuthError::Overloaded`, which
+/// keeps a flood of login attempts from piling up unbounded work.
+#[derive(Clone)]
+pub struct ValidationPool {
+    pool: CpuPool,
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: usize,
+}
+
+impl ValidationPool {
+    /// Creates a pool that validates credentials on `threads` threads.
+    pub fn new(threads: usize) -> ValidationPool {
+        ValidationPool {
+            pool: Builder::new()
+                .pool_size(threads)
+                .name_prefix("htpasswd-validate-")
+                .create(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight: usize::max_value(),
+        }
+    }
+
+    /// Limits the number of validations that can be queued or running
+    /// at the same time.
+    pub fn max_in_flight(mut self, max: usize) -> ValidationPool {
+        self.max_in_flight = max;
+        self
+    }
+
+    /// Checks the provided username and password against the database
+    /// like `PasswordDB::validate`, returning a future that resolves
+    /// once the pool has run the check.
+    pub fn validate(&self, db: &PasswordDB, user: &str, password: &str) -> ValidationFuture {
+        let verification = match db.prepare(user, password) {
+            Ok(verification) => verification,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let permit = match Permit::acquire(&self.in_flight, self.max_in_flight) {
+            Some(permit) => permit,
+            None => return Box::new(future::err(AuthError::Overloaded)),
+        };
+        Box::new(self.pool.spawn_fn(move || {
+            let _permit = permit;
+            verification.run()
+        }))
+    }
+
+    /// Like `validate`, but skips the bcrypt verification for
+    /// credentials that `cache` has seen validated recently, and adds
+    /// them to it once they have been.
+    pub fn validate_cached(
+        &self,
+        cache: &Arc<CredentialCache>,
+        db: &PasswordDB,
+        user: &str,
+        password: &str,
+    ) -> ValidationFuture {
+        let miss = match cache.lookup(db, user, password) {
+            Ok(Some(miss)) => miss,
+            Ok(None) => return Box::new(future::ok(())),
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let cache = cache.clone();
+        Box::new(
+            self.validate(db, user, password)
+                .map(move |()| cache.remember(miss)),
+        )
+    }
+}
+
+/// Counts towards the validations in flight until it is dropped.
+struct Permit(Arc<AtomicUsize>);
+
+impl Permit {
+    fn acquire(in_flight: &Arc<AtomicUsize>, max: usize) -> Option<Permit> {
+        let mut current = in_flight.load(Ordering::SeqCst);
+        loop {
+            if current >= max {
+                return None;
+            }
+            match in_flight.compare_exchange(
+                current,
+                current + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(Permit(in_flight.clone())),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_htpasswd_str, BadCredentials};
+    use std::time::Duration;
+
+    #[test]
+    fn validates() {
+        let db =
+            parse_htpasswd_str("asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96")
+                .unwrap();
+        let pool = ValidationPool::new(2);
+        assert_eq!(Ok(()), pool.validate(&db, "asf", "oink").wait());
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword)),
+            pool.validate(&db, "asf", "wrong").wait()
+        );
+        assert_eq!(
+            Err(AuthError::NotAuthenticated(BadCredentials::NoSuchUser)),
+            pool.validate(&db, "unperson", "oink").wait()
+        );
+    }
+
+    #[test]
+    fn limits_validations_in_flight() {
+        // A cost high enough that the first validation is still
+        // running when the second one is requested:
+        let hash = bcrypt::hash("oink", 12).unwrap();
+        let db = parse_htpasswd_str(&format!("asf:{}", hash)).unwrap();
+        let pool = ValidationPool::new(1).max_in_flight(1);
+
+        let first = pool.validate(&db, "asf", "oink");
+        assert_eq!(
+            Err(AuthError::Overloaded),
+            pool.validate(&db, "asf", "oink").wait()
+        );
+        assert_eq!(Ok(()), first.wait());
+        assert_eq!(0, pool.in_flight.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn caches() {
+        let db =
+            parse_htpasswd_str("asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96")
+                .unwrap();
+        let cache = Arc::new(CredentialCache::new(Duration::from_secs(60), 10));
+        // Cached credentials don't need the pool, which refuses all work:
+        let pool = ValidationPool::new(1).max_in_flight(0);
+        assert_eq!(Ok(()), cache.validate(&db, "asf", "oink"));
+        assert_eq!(
+            Ok(()),
+            pool.validate_cached(&cache, &db, "asf", "oink").wait()
+        );
+        assert_eq!(
+            Err(AuthError::Overloaded),
+            pool.validate_cached(&cache, &db, "asf", "wrong").wait()
+        );
+
+        let cache = Arc::new(CredentialCache::new(Duration::from_secs(60), 10));
+        let pool = ValidationPool::new(1);
+        assert_eq!(
+            Ok(()),
+            pool.validate_cached(&cache, &db, "asf", "oink").wait()
+        );
+        assert_eq!(1, cache.len());
+    }
+}
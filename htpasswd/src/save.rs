@@ -0,0 +1,323 @@
+use crate::{HtpasswdLoad, LoadFailure, PasswordDB};
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+/// The permissions that newly-created `.htpasswd` files get.
+#[cfg(unix)]
+const NEW_FILE_MODE: u32 = 0o600;
+
+/// A `.htpasswd` file that is locked against concurrent modification.
+///
+/// The lock is an exclusive advisory `flock` on the file itself, so it
+/// only keeps out other programs that lock the file too. Since the
+/// file is replaced rather than written to when saving, readers that
+/// don't lock (like `htpasswd_watcher`, or Apache) always see either
+/// the old or the new contents in full.
+///
+/// To safely update a file, lock it, `load` it, make changes to the
+/// database and then `save` it; the lock is held until the
+/// `LockedFile` is saved or dropped.
+#[derive(Debug)]
+pub struct LockedFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl LockedFile {
+    /// Locks the existing `.htpasswd` file at `path`, waiting for
+    /// other holders of the lock to release it.
+    pub fn lock<P: AsRef<Path>>(path: P) -> io::Result<LockedFile> {
+        LockedFile::open(path.as_ref(), false)
+    }
+
+    /// Like `lock`, but creates an empty file (readable only by its
+    /// owner) if there is none at `path` yet.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<LockedFile> {
+        LockedFile::open(path.as_ref(), true)
+    }
+
+    fn open(path: &Path, create: bool) -> io::Result<LockedFile> {
+        loop {
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).create(create);
+            #[cfg(unix)]
+            options.mode(NEW_FILE_MODE);
+            let file = options.open(path)?;
+            file.lock_exclusive()?;
+
+            // Whoever held the lock before us may have replaced the
+            // file, in which case we hold a lock on a stale copy:
+            if is_current(&file, path)? {
+                return Ok(LockedFile {
+                    path: path.to_path_buf(),
+                    file,
+                });
+            }
+        }
+    }
+
+    /// Reads the locked file and parses a `PasswordDB` from it. An
+    /// empty file is loaded as a database without any users.
+    pub fn load(&mut self) -> Result<PasswordDB, LoadFailure> {
+        self.file.seek(SeekFrom::Start(0))?;
+        if self.file.metadata()?.len() == 0 {
+            return Ok(PasswordDB::default());
+        }
+        self.file.load_htpasswd()
+    }
+
+    /// Atomically replaces the locked file with the contents of
+    /// `db`, in the charset it was loaded in (see
+    /// `PasswordDB::to_bytes`), and releases the lock.
+    ///
+    /// The new contents are written to a temporary file in the same
+    /// directory, flushed to disk, given the permissions and
+    /// ownership of the original, and then renamed over it.
+    pub fn save(self, db: &PasswordDB) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let name = self
+            .path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+        let tmp_path = dir.join(format!(
+            ".{}.{:016x}.tmp",
+            name.to_string_lossy(),
+            rand::random::<u64>()
+        ));
+
+        let result = self.write_replacement(&tmp_path, db);
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result?;
+        fs::rename(&tmp_path, &self.path)?;
+        sync_dir(dir)
+    }
+
+    fn write_replacement(&self, tmp_path: &Path, db: &PasswordDB) -> io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(NEW_FILE_MODE);
+        let mut tmp = options.open(tmp_path)?;
+
+        let original = self.file.metadata()?;
+        copy_ownership(&original, &tmp)?;
+        tmp.set_permissions(original.permissions())?;
+
+        tmp.write_all(&db.to_bytes())?;
+        tmp.sync_all()
+    }
+}
+
+/// Locks the `.htpasswd` file at `path` (creating it if necessary) and
+/// atomically replaces its contents with `db`.
+///
+/// Note that any changes that were made to the file since `db` was
+/// loaded are lost; use `LockedFile` to load and save a file under the
+/// same lock.
+pub fn save_htpasswd<P: AsRef<Path>>(path: P, db: &PasswordDB) -> io::Result<()> {
+    LockedFile::create(path)?.save(db)
+}
+
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> io::Result<bool> {
+    let locked = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(locked.dev() == current.dev() && locked.ino() == current.ino()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn is_current(_file: &File, _path: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn copy_ownership(original: &fs::Metadata, tmp: &File) -> io::Result<()> {
+    let current = tmp.metadata()?;
+    if current.uid() == original.uid() && current.gid() == original.gid() {
+        return Ok(());
+    }
+    change_owner(tmp, original.uid(), original.gid())
+}
+
+/// Hands `tmp` over to `uid` and `gid` as far as we're allowed to.
+///
+/// Only root may give files away, so everyone else saving a file
+/// owned by another user (that they can write to through its group,
+/// say) keeps the group if they're a member of it, and otherwise
+/// saves the file as their own.
+#[cfg(unix)]
+fn change_owner(tmp: &File, uid: u32, gid: u32) -> io::Result<()> {
+    let fchown = |uid, gid| match unsafe { libc::fchown(tmp.as_raw_fd(), uid, gid) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    };
+    let not_permitted = |e: &io::Error| e.raw_os_error() == Some(libc::EPERM);
+    match fchown(uid, gid) {
+        Err(ref e) if not_permitted(e) => match fchown(!0, gid) {
+            Err(ref e) if not_permitted(e) => Ok(()),
+            result => result,
+        },
+        result => result,
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_ownership(_original: &fs::Metadata, _tmp: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Makes sure a rename in `dir` survives a crash.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_htpasswd_str, UpdateError};
+    use std::env;
+    use std::str;
+
+    const CONTENTS: &str = "asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96\n";
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("htpasswd-{}-{:x}", name, rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn creates_and_loads() {
+        let dir = scratch_dir("create");
+        let path = dir.join("htpasswd");
+        let db = parse_htpasswd_str(CONTENTS.trim_end()).unwrap();
+        save_htpasswd(&path, &db).unwrap();
+
+        assert_eq!(CONTENTS, fs::read_to_string(&path).unwrap());
+        assert_eq!(db, LockedFile::lock(&path).unwrap().load().unwrap());
+        // only the saved file is left over:
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_latin1() {
+        let dir = scratch_dir("latin1");
+        let path = dir.join("htpasswd");
+        // "müller:grün", written and hashed in Latin-1:
+        let mut contents = b"m\xfcller:".to_vec();
+        contents.extend(bcrypt::hash(&b"gr\xfcn"[..], 4).unwrap().as_bytes());
+        contents.push(b'\n');
+        fs::write(&path, &contents).unwrap();
+
+        let mut locked = LockedFile::lock(&path).unwrap();
+        let mut db = locked.load().unwrap();
+        assert_eq!(Ok(()), db.validate("m\u{fc}ller", "gr\u{fc}n"));
+        assert_eq!(Ok(()), db.set_password("j\u{f6}rg", "s\u{fc}\u{df}", 4));
+        assert_eq!(
+            Err(UpdateError::BadUsername),
+            db.set_password("\u{141}ukasz", "oink", 4)
+        );
+        locked.save(&db).unwrap();
+
+        let saved = fs::read(&path).unwrap();
+        assert!(saved.starts_with(&contents));
+        assert!(str::from_utf8(&saved).is_err());
+        let db = LockedFile::lock(&path).unwrap().load().unwrap();
+        assert_eq!(Ok(()), db.validate("m\u{fc}ller", "gr\u{fc}n"));
+        assert_eq!(Ok(()), db.validate("j\u{f6}rg", "s\u{fc}\u{df}"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn empty_file() {
+        let dir = scratch_dir("empty");
+        let path = dir.join("htpasswd");
+        assert_eq!(
+            PasswordDB::default(),
+            LockedFile::create(&path).unwrap().load().unwrap()
+        );
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            LockedFile::lock(dir.join("missing")).unwrap_err().kind()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("perms");
+        let path = dir.join("htpasswd");
+        fs::write(&path, CONTENTS).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut locked = LockedFile::lock(&path).unwrap();
+        let db = locked.load().unwrap();
+        locked.save(&db).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o640, mode & 0o777);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_ownership_if_permitted() {
+        let dir = scratch_dir("owner");
+        let file = File::create(dir.join("htpasswd")).unwrap();
+        let uid = unsafe { libc::getuid() };
+        let gid = unsafe { libc::getgid() };
+
+        // Unless we run as root, neither root's user nor (usually) its
+        // group can be given the file, which is saved as ours then:
+        change_owner(&file, 0, 0).unwrap();
+        let owner = file.metadata().unwrap();
+        if uid != 0 {
+            assert_eq!(uid, owner.uid());
+        }
+        change_owner(&file, uid, gid).unwrap();
+        let owner = file.metadata().unwrap();
+        assert_eq!((uid, gid), (owner.uid(), owner.gid()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relocks_replaced_file() {
+        let dir = scratch_dir("relock");
+        let path = dir.join("htpasswd");
+        fs::write(&path, CONTENTS).unwrap();
+
+        let stale = File::open(&path).unwrap();
+        assert!(is_current(&stale, &path).unwrap());
+        LockedFile::lock(&path)
+            .unwrap()
+            .save(&PasswordDB::default())
+            .unwrap();
+        assert!(!is_current(&stale, &path).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
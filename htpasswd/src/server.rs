@@ -0,0 +1,114 @@
+use crate::Charset;
+
+/// The realm that clients are challenged for unless configured
+/// otherwise.
+pub const DEFAULT_REALM: &str = "Restricted";
+
+/// Renders `s` as an HTTP quoted-string.
+pub(crate) fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns the value of a `WWW-Authenticate` (or `Proxy-Authenticate`)
+/// header that asks for HTTP Basic credentials for `realm`. If they
+/// are decoded according to `Charset::Utf8`, the challenge announces
+/// (as RFC 7617 allows) that they are expected to be encoded in
+/// UTF-8; otherwise, clients are left to send them in their legacy
+/// charset.
+pub fn basic_challenge(realm: &str, charset: Charset) -> String {
+    match charset {
+        Charset::Utf8 => format!("Basic realm={}, charset=\"UTF-8\"", quote(realm)),
+        Charset::Utf8OrLatin1 | Charset::MatchFile => format!("Basic realm={}", quote(realm)),
+    }
+}
+
+/// Returns true if `realm` can be sent in a challenge: Header values
+/// can't hold control characters, and clients disagree on how to
+/// decode anything but ASCII, so realms must be printable ASCII.
+pub fn is_valid_realm(realm: &str) -> bool {
+    realm
+        .bytes()
+        .all(|b| b == b'\t' || (b' '..=b'~').contains(&b))
+}
+
+/// Returns `realm`, or panics if it can't be sent to clients (see
+/// `is_valid_realm`), so that a bad realm is noticed when a service is
+/// configured rather than by challenges going missing.
+pub fn checked_realm(realm: &str) -> String {
+    assert!(
+        is_valid_realm(realm),
+        "realm {:?} can't be sent in a challenge: it must be printable ASCII",
+        realm
+    );
+    realm.to_string()
+}
+
+/// The identity of a user that logged in, which the authentication
+/// middleware built on this crate inserts into a request's extensions
+/// before passing it on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthenticatedUser {
+    pub username: String,
+
+    /// The groups the user is a member of, in alphabetical order, if
+    /// group memberships were configured and could be loaded.
+    pub groups: Option<Vec<String>>,
+}
+
+impl AuthenticatedUser {
+    /// Identifies a user without looking up their groups.
+    pub fn new(username: String) -> AuthenticatedUser {
+        AuthenticatedUser {
+            username,
+            groups: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge() {
+        assert_eq!(
+            "Basic realm=\"Restricted\", charset=\"UTF-8\"",
+            basic_challenge(DEFAULT_REALM, Charset::Utf8)
+        );
+        assert_eq!(
+            "Basic realm=\"a \\\"quoted\\\" \\\\ realm\", charset=\"UTF-8\"",
+            basic_challenge("a \"quoted\" \\ realm", Charset::Utf8)
+        );
+        for &charset in &[Charset::Utf8OrLatin1, Charset::MatchFile] {
+            assert_eq!(
+                "Basic realm=\"Restricted\"",
+                basic_challenge(DEFAULT_REALM, charset)
+            );
+        }
+    }
+
+    #[test]
+    fn realms() {
+        assert!(is_valid_realm(DEFAULT_REALM));
+        assert!(is_valid_realm("Jane's \"stuff\"\t~"));
+        assert!(!is_valid_realm("M\u{fc}ller"));
+        assert!(!is_valid_realm("two\nlines"));
+        assert!(!is_valid_realm("\x7f"));
+        assert_eq!("Private", checked_realm("Private"));
+    }
+
+    #[test]
+    #[should_panic(expected = "must be printable ASCII")]
+    fn bad_realm() {
+        checked_realm("Private\r\nX-Injected: 1");
+    }
+}
@@ -0,0 +1,460 @@
+use crate::{AuthError, PasswordDB};
+use std::cmp;
+use std::collections::hash_map::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+struct Attempts {
+    /// Failed attempts, since the last success or since older ones
+    /// were forgotten.
+    failures: u32,
+
+    /// Attempts started with `Throttle::begin` that haven't been
+    /// settled with `Throttle::finish` yet.
+    pending: u32,
+
+    /// When the last failure (or, before there was one, attempt)
+    /// happened.
+    last: Instant,
+}
+
+/// The attempts of all users and clients, kept under one lock so that
+/// they are checked and counted atomically.
+#[derive(Debug)]
+struct State<K> {
+    users: HashMap<String, Attempts>,
+    clients: HashMap<K, Attempts>,
+}
+
+/// Slows down password guessing by refusing to validate credentials
+/// after repeated failures, both for the user name being tried and for
+/// the client trying it (typically identified by its IP address).
+///
+/// Once a user or client has accumulated `max_failures` consecutive
+/// failed attempts, further attempts are rejected with
+/// `AuthError::Throttled` for a delay that starts out at the base
+/// delay and doubles with every further failure, up to the maximum
+/// delay. A successful validation clears the failures for the user,
+/// but not those of the client, so that logging in to one account
+/// between guesses at the passwords of others doesn't reset the count.
+/// Failures that are older than the `forget_after` period are
+/// discarded.
+///
+/// Note that throttling by user name allows anyone to make a user's
+/// login slower by guessing their password; the backoff limit bounds
+/// how long that lasts.
+///
+/// All state is kept in memory, bounded to `max_entries` users and
+/// clients each; when that limit is reached, the older half of the
+/// entries is discarded, apart from those with attempts under way. A
+/// limit of 0 disables throttling.
+#[derive(Debug)]
+pub struct Throttle<K = IpAddr>
+where
+    K: Hash + Eq,
+{
+    max_failures: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    forget_after: Duration,
+    max_entries: usize,
+    state: Mutex<State<K>>,
+}
+
+impl<K> Default for Throttle<K>
+where
+    K: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Throttle::new()
+    }
+}
+
+impl<K> Throttle<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Creates a throttle that allows 5 failures before delaying
+    /// further attempts by 1 second, doubling up to 15 minutes, and
+    /// forgets failures after an hour.
+    pub fn new() -> Throttle<K> {
+        Throttle {
+            max_failures: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(15 * 60),
+            forget_after: Duration::from_secs(60 * 60),
+            max_entries: 10_000,
+            state: Mutex::new(State {
+                users: HashMap::new(),
+                clients: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Sets the number of consecutive failures that are allowed
+    /// before attempts get delayed.
+    pub fn max_failures(mut self, failures: u32) -> Self {
+        self.max_failures = failures;
+        self
+    }
+
+    /// Sets the delay imposed after `max_failures` failures, and the
+    /// limit up to which further failures double it. Setting both to
+    /// the same value results in a fixed-length lockout.
+    pub fn backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.base_delay = base;
+        self.max_delay = cmp::max(base, max);
+        self
+    }
+
+    /// Sets the period without failures after which a user's or
+    /// client's failure count is reset.
+    pub fn forget_after(mut self, period: Duration) -> Self {
+        self.forget_after = period;
+        self
+    }
+
+    /// Sets the number of users and clients (each) that failures are
+    /// tracked for. With 0, no failures are tracked, so nothing is
+    /// ever throttled.
+    pub fn max_entries(mut self, entries: usize) -> Self {
+        self.max_entries = entries;
+        self
+    }
+
+    /// Returns `Err(AuthError::Throttled)` if attempts to log in as
+    /// `user` or from `client` are currently being delayed.
+    pub fn check(&self, user: &str, client: &K) -> Result<(), AuthError> {
+        self.check_locked(&self.lock(), user, client)
+    }
+
+    /// Records the result of an attempt to log in as `user` from
+    /// `client`. Only rejected credentials count as failures; errors
+    /// that aren't the client's fault are ignored.
+    pub fn record(&self, user: &str, client: &K, result: &Result<(), AuthError>) {
+        let mut state = self.lock();
+        self.settle(&mut state.users, user.to_string(), result, true);
+        self.settle(&mut state.clients, client.clone(), result, false);
+    }
+
+    /// Like `check`, but also registers the attempt as under way, so
+    /// that its entries are kept until the outcome is passed to
+    /// `finish`. Attempts under way don't count as failures, so any
+    /// number of concurrent attempts with correct credentials go
+    /// through.
+    pub fn begin(&self, user: &str, client: &K) -> Result<(), AuthError> {
+        let mut state = self.lock();
+        self.check_locked(&state, user, client)?;
+        let now = Instant::now();
+        if let Some(attempts) = self.attempts(&mut state.users, user.to_string(), now) {
+            attempts.pending = attempts.pending.saturating_add(1);
+        }
+        if let Some(attempts) = self.attempts(&mut state.clients, client.clone(), now) {
+            attempts.pending = attempts.pending.saturating_add(1);
+        }
+        Ok(())
+    }
+
+    /// Settles an attempt started with `begin`, recording its outcome
+    /// like `record`.
+    pub fn finish(&self, user: &str, client: &K, result: &Result<(), AuthError>) {
+        self.record(user, client, result);
+    }
+
+    /// Checks the provided credentials against the database like
+    /// `PasswordDB::validate`, unless they're being throttled, and
+    /// records the outcome.
+    pub fn validate(
+        &self,
+        db: &PasswordDB,
+        user: &str,
+        password: &str,
+        client: &K,
+    ) -> Result<(), AuthError> {
+        self.begin(user, client)?;
+        let result = db.validate(user, password);
+        self.finish(user, client, &result);
+        result
+    }
+
+    fn lock(&self) -> MutexGuard<State<K>> {
+        // Entries are only ever updated whole, so the maps are
+        // consistent even if a thread panicked while holding them:
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check_locked(&self, state: &State<K>, user: &str, client: &K) -> Result<(), AuthError> {
+        let now = Instant::now();
+        let user_until = state.users.get(user).and_then(|f| self.delayed_until(f));
+        let client_until = state
+            .clients
+            .get(client)
+            .and_then(|f| self.delayed_until(f));
+        match cmp::max(user_until, client_until) {
+            Some(until) if until > now => Err(AuthError::Throttled(until - now)),
+            _ => Ok(()),
+        }
+    }
+
+    fn delayed_until(&self, attempts: &Attempts) -> Option<Instant> {
+        if attempts.failures < self.max_failures {
+            return None;
+        }
+        let doublings = cmp::min(attempts.failures - self.max_failures, 31);
+        let delay = self
+            .base_delay
+            .checked_mul(1 << doublings)
+            .map_or(self.max_delay, |delay| cmp::min(delay, self.max_delay));
+        Some(attempts.last + delay)
+    }
+
+    fn is_forgotten(&self, attempts: &Attempts, now: Instant) -> bool {
+        now.duration_since(attempts.last) >= self.forget_after
+    }
+
+    /// Settles an attempt (whether it was begun or not) in the entry
+    /// for `key`: rejected credentials count as a failure, and a
+    /// success clears the failures if `success_clears` is set.
+    fn settle<T: Hash + Eq + Clone>(
+        &self,
+        entries: &mut HashMap<T, Attempts>,
+        key: T,
+        result: &Result<(), AuthError>,
+        success_clears: bool,
+    ) {
+        let now = Instant::now();
+        if let Some(attempts) = self.attempts(entries, key.clone(), now) {
+            attempts.pending = attempts.pending.saturating_sub(1);
+            match result {
+                Ok(()) if success_clears => attempts.failures = 0,
+                Err(AuthError::NotAuthenticated(_)) => {
+                    attempts.failures = attempts.failures.saturating_add(1);
+                    attempts.last = now;
+                }
+                _ => {}
+            }
+            if attempts.failures == 0 && attempts.pending == 0 {
+                entries.remove(&key);
+            }
+        }
+    }
+
+    /// Returns the entry for `key`, adding it if necessary, or `None`
+    /// if no entries are kept at all.
+    fn attempts<'a, T: Hash + Eq>(
+        &self,
+        entries: &'a mut HashMap<T, Attempts>,
+        key: T,
+        now: Instant,
+    ) -> Option<&'a mut Attempts> {
+        if self.max_entries == 0 {
+            return None;
+        }
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            self.make_room(entries, now);
+        }
+        let attempts = entries.entry(key).or_insert(Attempts {
+            failures: 0,
+            pending: 0,
+            last: now,
+        });
+        if self.is_forgotten(attempts, now) {
+            attempts.failures = 0;
+        }
+        Some(attempts)
+    }
+
+    /// Discards forgotten entries and, if that isn't enough, the older
+    /// half of the rest, so that a stream of new keys only pays for
+    /// sorting once every `max_entries / 2` of them. Entries with
+    /// attempts under way are kept.
+    fn make_room<T: Hash + Eq>(&self, entries: &mut HashMap<T, Attempts>, now: Instant) {
+        entries.retain(|_, attempts| attempts.pending > 0 || !self.is_forgotten(attempts, now));
+        if entries.len() < self.max_entries {
+            return;
+        }
+        let mut by_age: Vec<Instant> = entries
+            .values()
+            .filter(|attempts| attempts.pending == 0)
+            .map(|attempts| attempts.last)
+            .collect();
+        by_age.sort_unstable();
+        if let Some(&cutoff) = by_age.get(by_age.len() / 2) {
+            entries.retain(|_, attempts| attempts.pending > 0 || attempts.last > cutoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BadCredentials;
+
+    const FAILED: Result<(), AuthError> =
+        Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword));
+
+    fn throttle() -> Throttle<&'static str> {
+        Throttle::new()
+            .max_failures(2)
+            .backoff(Duration::from_secs(10), Duration::from_secs(40))
+    }
+
+    fn retry_after(result: Result<(), AuthError>) -> Option<Duration> {
+        match result {
+            Err(AuthError::Throttled(after)) => Some(after),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn per_user() {
+        let throttle = throttle();
+        throttle.record("asf", &"client1", &FAILED);
+        throttle.record("asf", &"client2", &FAILED);
+        assert!(retry_after(throttle.check("asf", &"client3")).is_some());
+        assert_eq!(Ok(()), throttle.check("bsf", &"client3"));
+    }
+
+    #[test]
+    fn per_client() {
+        let throttle = throttle();
+        throttle.record("asf", &"client1", &FAILED);
+        throttle.record("bsf", &"client1", &FAILED);
+        assert!(retry_after(throttle.check("csf", &"client1")).is_some());
+        assert_eq!(Ok(()), throttle.check("csf", &"client2"));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let throttle = throttle();
+        let mut delays = vec![];
+        for _ in 0..5 {
+            throttle.record("asf", &"client1", &FAILED);
+            delays.push(retry_after(throttle.check("asf", &"client1")));
+        }
+        let secs: Vec<Option<u64>> = delays
+            .into_iter()
+            .map(|d| d.map(|d| (d + Duration::from_millis(500)).as_secs()))
+            .collect();
+        assert_eq!(vec![None, Some(10), Some(20), Some(40), Some(40)], secs);
+    }
+
+    #[test]
+    fn success_resets() {
+        let throttle = throttle();
+        throttle.record("asf", &"client1", &FAILED);
+        throttle.record("asf", &"client2", &Ok(()));
+        throttle.record("asf", &"client3", &FAILED);
+        assert_eq!(Ok(()), throttle.check("asf", &"client4"));
+    }
+
+    #[test]
+    fn success_keeps_client_failures() {
+        let throttle = throttle();
+        // Logging in to one account between guesses at others:
+        for user in &["bsf", "csf"] {
+            throttle.record("asf", &"client1", &Ok(()));
+            throttle.record(user, &"client1", &FAILED);
+        }
+        throttle.record("asf", &"client1", &Ok(()));
+        assert!(retry_after(throttle.check("dsf", &"client1")).is_some());
+        assert_eq!(Ok(()), throttle.check("dsf", &"client2"));
+    }
+
+    #[test]
+    fn storage_errors_dont_count() {
+        let throttle = throttle();
+        throttle.record("asf", &"client1", &Err(AuthError::Overloaded));
+        throttle.record("asf", &"client1", &Err(AuthError::Overloaded));
+        assert_eq!(Ok(()), throttle.check("asf", &"client1"));
+    }
+
+    #[test]
+    fn forgets_old_failures() {
+        let throttle = throttle().forget_after(Duration::from_secs(0));
+        throttle.record("asf", &"client1", &FAILED);
+        throttle.record("asf", &"client1", &FAILED);
+        throttle.record("asf", &"client1", &FAILED);
+        assert_eq!(1, throttle.lock().users["asf"].failures);
+    }
+
+    #[test]
+    fn bounded_size() {
+        let throttle = throttle().max_entries(2);
+        for user in &["asf", "bsf", "csf", "dsf"] {
+            throttle.record(user, &"client1", &FAILED);
+        }
+        let state = throttle.lock();
+        assert_eq!(2, state.users.len());
+        assert!(state.users.contains_key("dsf"));
+    }
+
+    #[test]
+    fn no_entries() {
+        let throttle = throttle().max_entries(0);
+        for _ in 0..3 {
+            throttle.record("asf", &"client1", &FAILED);
+            assert_eq!(Ok(()), throttle.begin("asf", &"client1"));
+            throttle.finish("asf", &"client1", &FAILED);
+        }
+        assert_eq!(Ok(()), throttle.check("asf", &"client1"));
+        assert!(throttle.lock().users.is_empty());
+    }
+
+    #[test]
+    fn concurrent_attempts() {
+        let throttle = throttle();
+        // More correct logins than max_failures are under way at once:
+        for _ in 0..3 {
+            assert_eq!(Ok(()), throttle.begin("asf", &"client1"));
+        }
+        for _ in 0..3 {
+            throttle.finish("asf", &"client1", &Ok(()));
+        }
+        assert_eq!(Ok(()), throttle.begin("asf", &"client1"));
+        throttle.finish("asf", &"client1", &Ok(()));
+        assert!(throttle.lock().users.is_empty());
+
+        // Failures count once they are confirmed:
+        assert_eq!(Ok(()), throttle.begin("asf", &"client1"));
+        assert_eq!(Ok(()), throttle.begin("asf", &"client2"));
+        throttle.finish("asf", &"client1", &FAILED);
+        throttle.finish("asf", &"client2", &FAILED);
+        assert!(retry_after(throttle.begin("asf", &"client3")).is_some());
+    }
+
+    #[test]
+    fn finished_attempts() {
+        let throttle = throttle();
+        throttle.begin("asf", &"client1").unwrap();
+        throttle.finish("asf", &"client1", &Err(AuthError::Overloaded));
+        throttle.begin("asf", &"client1").unwrap();
+        throttle.finish("asf", &"client1", &Err(AuthError::Overloaded));
+        assert!(throttle.lock().users.is_empty());
+        assert!(throttle.lock().clients.is_empty());
+
+        throttle.begin("asf", &"client1").unwrap();
+        throttle.finish("asf", &"client1", &FAILED);
+        throttle.begin("asf", &"client1").unwrap();
+        throttle.finish("asf", &"client1", &Ok(()));
+        let state = throttle.lock();
+        assert!(state.users.is_empty());
+        assert_eq!(1, state.clients["client1"].failures);
+        assert_eq!(0, state.clients["client1"].pending);
+    }
+
+    #[test]
+    fn pending_attempts_are_kept() {
+        let throttle = throttle().max_entries(2);
+        throttle.begin("asf", &"client1").unwrap();
+        for user in &["bsf", "csf", "dsf"] {
+            throttle.record(user, &"client2", &FAILED);
+        }
+        assert!(throttle.lock().users.contains_key("asf"));
+        throttle.finish("asf", &"client1", &FAILED);
+        assert_eq!(1, throttle.lock().users["asf"].failures);
+    }
+}
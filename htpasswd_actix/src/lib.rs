@@ -0,0 +1,453 @@
+//! # `htpasswd_actix` - Authenticate actix-web requests against `.htpasswd` files.
+//!
+//! The `Authenticate` middleware lets through requests with valid
+//! HTTP Basic credentials, like `htpasswd_hyper::Authenticate`:
+//!
+//! ```rust,ignore
+//! async fn private(user: web::ReqData<AuthenticatedUser>) -> String {
+//!     format!("Hello, {}!", user.username)
+//! }
+//!
+//! let app = App::new()
+//!     .wrap(Authenticate::new(source).realm("Private"))
+//!     .route("/", web::get().to(private));
+//! ```
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpResponse};
+use htpasswd::{checked_realm, Charset, GroupDBSource, PasswordDBSource};
+use std::error::Error;
+use std::future::{ready, Future, Ready};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use htpasswd::{basic_challenge, AuthenticatedUser, DEFAULT_REALM};
+
+/// Returns a `Retry-After` value in seconds, rounded up so that
+/// clients don't retry too early.
+fn retry_after_seconds(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+/// Receives the errors that occur when loading the password DB.
+type ErrorHook = Arc<dyn Fn(&dyn Error) + Send + Sync>;
+
+/// Looks up the groups a user is a member of.
+type GroupLookup = Arc<dyn Fn(&str) -> Option<Vec<String>> + Send + Sync>;
+
+/// Why a request wasn't passed on.
+enum Rejection {
+    /// The credentials are missing or wrong.
+    Unauthorized,
+
+    /// The password DB couldn't be loaded.
+    LoadError,
+}
+
+struct Config<S> {
+    source: Arc<S>,
+    realm: String,
+    charset: Charset,
+    groups: Option<GroupLookup>,
+    strip_authorization: bool,
+    load_error_hook: Option<ErrorHook>,
+    load_error_status: StatusCode,
+    retry_after: Option<Duration>,
+}
+
+impl<S> Clone for Config<S> {
+    fn clone(&self) -> Self {
+        Config {
+            source: self.source.clone(),
+            realm: self.realm.clone(),
+            charset: self.charset,
+            groups: self.groups.clone(),
+            strip_authorization: self.strip_authorization,
+            load_error_hook: self.load_error_hook.clone(),
+            load_error_status: self.load_error_status,
+            retry_after: self.retry_after,
+        }
+    }
+}
+
+impl<S> Config<S>
+where
+    S: PasswordDBSource,
+{
+    /// Checks the value of a request's `Authorization` header,
+    /// returning the identity of the user who sent it. This runs
+    /// bcrypt, so it blocks.
+    fn check(&self, authorization: Option<&[u8]>) -> Result<AuthenticatedUser, Rejection> {
+        let db = self.source.get();
+        let db = match db.deref() {
+            Ok(db) => db,
+            Err(e) => {
+                if let Some(hook) = &self.load_error_hook {
+                    hook(e);
+                }
+                return Err(Rejection::LoadError);
+            }
+        };
+        let authorization = authorization.ok_or(Rejection::Unauthorized)?;
+        let username = db
+            .validate_basic_header_as(authorization, self.charset)
+            .map_err(|_| Rejection::Unauthorized)?;
+        let groups = self.groups.as_ref().and_then(|lookup| lookup(&username));
+        Ok(AuthenticatedUser { username, groups })
+    }
+
+    /// Builds the response to a rejected request.
+    fn respond(&self, rejection: Rejection) -> HttpResponse {
+        match rejection {
+            Rejection::Unauthorized => HttpResponse::Unauthorized()
+                .insert_header((
+                    header::WWW_AUTHENTICATE,
+                    basic_challenge(&self.realm, self.charset),
+                ))
+                .body("Unauthorized."),
+            Rejection::LoadError => {
+                let status = self.load_error_status;
+                let mut response = HttpResponse::build(status);
+                if let Some(retry_after) = self.retry_after {
+                    response.insert_header((header::RETRY_AFTER, retry_after_seconds(retry_after)));
+                }
+                response.body(format!("{}.", status.canonical_reason().unwrap_or("Error")))
+            }
+        }
+    }
+}
+
+/// A middleware that passes requests with valid HTTP Basic
+/// credentials on, with an `AuthenticatedUser` in their extensions.
+/// Others are answered with `401 Unauthorized` and a challenge, or
+/// `500 Internal Server Error` if the password DB can't be loaded.
+///
+/// Credentials are validated with `web::block`, so that bcrypt doesn't
+/// hold up the worker.
+pub struct Authenticate<S> {
+    config: Config<S>,
+}
+
+impl<S> Authenticate<S>
+where
+    S: PasswordDBSource,
+{
+    /// Authenticates requests against the password DB that `source`
+    /// provides.
+    pub fn new(source: S) -> Self {
+        Authenticate {
+            config: Config {
+                source: Arc::new(source),
+                realm: DEFAULT_REALM.to_string(),
+                charset: Charset::default(),
+                groups: None,
+                strip_authorization: false,
+                load_error_hook: None,
+                load_error_status: StatusCode::INTERNAL_SERVER_ERROR,
+                retry_after: None,
+            },
+        }
+    }
+
+    /// Sets the realm that clients are asked to log in to, which
+    /// browsers show in their login prompt. Defaults to
+    /// `DEFAULT_REALM`.
+    ///
+    /// # Panics
+    ///
+    /// If the realm isn't printable ASCII (see
+    /// `htpasswd::is_valid_realm`), since it couldn't be sent to
+    /// clients.
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.config.realm = checked_realm(realm);
+        self
+    }
+
+    /// Decodes credentials according to `charset`. Defaults to
+    /// `Charset::Utf8`, which challenges announce to clients; with
+    /// other charsets, they leave the `charset` parameter out.
+    pub fn charset(mut self, charset: Charset) -> Self {
+        self.config.charset = charset;
+        self
+    }
+
+    /// Looks up the groups of authenticated users in `groups`, to
+    /// pass them on in the `AuthenticatedUser`.
+    pub fn groups<G>(mut self, groups: G) -> Self
+    where
+        G: GroupDBSource + Send + Sync + 'static,
+    {
+        self.config.groups = Some(Arc::new(move |user| match groups.get().deref() {
+            Ok(db) => Some(db.groups_of(user).into_iter().map(String::from).collect()),
+            Err(_) => None,
+        }));
+        self
+    }
+
+    /// Removes the `Authorization` header (and with it, the password)
+    /// from authenticated requests before passing them on.
+    pub fn strip_authorization(mut self) -> Self {
+        self.config.strip_authorization = true;
+        self
+    }
+
+    /// Passes errors from loading the password DB to `hook`, e.g. to
+    /// log them. Clients only ever see a generic error response.
+    pub fn on_load_error<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&dyn Error) + Send + Sync + 'static,
+    {
+        self.config.load_error_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Responds with `503 Service Unavailable` instead of `500
+    /// Internal Server Error` when the password DB can't be loaded,
+    /// optionally telling clients when to retry.
+    pub fn respond_unavailable(mut self, retry_after: Option<Duration>) -> Self {
+        self.config.load_error_status = StatusCode::SERVICE_UNAVAILABLE;
+        self.config.retry_after = retry_after;
+        self
+    }
+}
+
+impl<S> Clone for Authenticate<S> {
+    fn clone(&self) -> Self {
+        Authenticate {
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<T, S, B> Transform<T, ServiceRequest> for Authenticate<S>
+where
+    T: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S: PasswordDBSource + Send + Sync + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = AuthenticateMiddleware<T, S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: T) -> Self::Future {
+        ready(Ok(AuthenticateMiddleware {
+            service: Rc::new(service),
+            config: Arc::new(self.config.clone()),
+        }))
+    }
+}
+
+/// The service that `Authenticate` wraps around the inner one.
+pub struct AuthenticateMiddleware<T, S> {
+    service: Rc<T>,
+    config: Arc<Config<S>>,
+}
+
+impl<T, S, B> Service<ServiceRequest> for AuthenticateMiddleware<T, S>
+where
+    T: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    S: PasswordDBSource + Send + Sync + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let config = self.config.clone();
+        let authorization = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .map(|value| value.as_bytes().to_vec());
+        Box::pin(async move {
+            let checking = config.clone();
+            let checked = web::block(move || checking.check(authorization.as_deref())).await?;
+            match checked {
+                Ok(user) => {
+                    if config.strip_authorization {
+                        request.headers_mut().remove(header::AUTHORIZATION);
+                    }
+                    request.extensions_mut().insert(user);
+                    let response = service.call(request).await?;
+                    Ok(response.map_into_left_body())
+                }
+                Err(rejection) => {
+                    let response = config.respond(rejection);
+                    Ok(request.into_response(response).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpRequest};
+    use htpasswd::{parse_htgroup_str, parse_htpasswd_str, GroupDB, LoadFailure, PasswordDB};
+    use std::io;
+    use std::sync::Mutex;
+
+    /// A source that never changes.
+    type Loaded<T> = Arc<Result<T, LoadFailure>>;
+
+    fn passwords() -> Loaded<PasswordDB> {
+        Arc::new(Ok(parse_htpasswd_str(
+            "asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96",
+        )
+        .unwrap()))
+    }
+
+    fn groups() -> Loaded<GroupDB> {
+        Arc::new(Ok(parse_htgroup_str("admins: asf\nstaff: bsf\n").unwrap()))
+    }
+
+    fn broken() -> Loaded<PasswordDB> {
+        let error = io::Error::new(io::ErrorKind::Other, "unreadable");
+        Arc::new(Err(LoadFailure::Io(error)))
+    }
+
+    async fn whoami(user: web::ReqData<AuthenticatedUser>) -> String {
+        format!("Hello, {}!", user.username)
+    }
+
+    /// Answers with the identity it was passed, and whether the
+    /// request still has an `Authorization` header.
+    async fn echo(user: web::ReqData<AuthenticatedUser>, request: HttpRequest) -> String {
+        format!(
+            "{:?} {}",
+            user.into_inner(),
+            request.headers().contains_key(header::AUTHORIZATION)
+        )
+    }
+
+    /// Fetches `/private` from `server`, returning the response and
+    /// its body.
+    async fn get(
+        server: &actix_test::TestServer,
+        authorization: Option<&str>,
+    ) -> (awc::ClientResponse, String) {
+        let mut request = server.get("/private");
+        if let Some(authorization) = authorization {
+            request = request.insert_header((header::AUTHORIZATION, authorization));
+        }
+        let mut response = request.send().await.unwrap();
+        let body = response.body().await.unwrap();
+        (response, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    // "asf:oink" and "asf:wrong":
+    const CORRECT: &str = "Basic YXNmOm9pbms=";
+    const WRONG: &str = "Basic YXNmOndyb25n";
+
+    #[actix_web::test]
+    async fn authenticates() {
+        let server = actix_test::start(|| {
+            App::new()
+                .wrap(Authenticate::new(passwords()).realm("Private"))
+                .route("/private", web::get().to(whoami))
+        });
+
+        let (response, body) = get(&server, Some(CORRECT)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("Hello, asf!", body);
+
+        for authorization in &[None, Some(WRONG), Some("Basic")] {
+            let (response, body) = get(&server, *authorization).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+            assert_eq!(
+                "Basic realm=\"Private\", charset=\"UTF-8\"",
+                response.headers()[header::WWW_AUTHENTICATE]
+            );
+            assert_eq!("Unauthorized.", body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn forwards_identity() {
+        let server = actix_test::start(|| {
+            App::new()
+                .wrap(Authenticate::new(passwords()))
+                .route("/private", web::get().to(echo))
+        });
+        let (_, body) = get(&server, Some(CORRECT)).await;
+        assert_eq!(
+            "AuthenticatedUser { username: \"asf\", groups: None } true",
+            body
+        );
+
+        let server = actix_test::start(|| {
+            App::new()
+                .wrap(
+                    Authenticate::new(passwords())
+                        .groups(groups())
+                        .strip_authorization(),
+                )
+                .route("/private", web::get().to(echo))
+        });
+        let (_, body) = get(&server, Some(CORRECT)).await;
+        assert_eq!(
+            "AuthenticatedUser { username: \"asf\", groups: Some([\"admins\"]) } false",
+            body
+        );
+    }
+
+    #[actix_web::test]
+    async fn hides_load_errors() {
+        let server = actix_test::start(|| {
+            App::new()
+                .wrap(Authenticate::new(broken()))
+                .route("/private", web::get().to(whoami))
+        });
+        let (response, body) = get(&server, Some(CORRECT)).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        assert_eq!("Internal Server Error.", body);
+
+        let logged = Arc::new(Mutex::new(vec![]));
+        let log = logged.clone();
+        let server = actix_test::start(move || {
+            let log = log.clone();
+            App::new()
+                .wrap(
+                    Authenticate::new(broken())
+                        .on_load_error(move |e| log.lock().unwrap().push(e.to_string()))
+                        .respond_unavailable(Some(Duration::from_millis(1500))),
+                )
+                .route("/private", web::get().to(whoami))
+        });
+        let (response, body) = get(&server, Some(CORRECT)).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!("2", response.headers()[header::RETRY_AFTER]);
+        assert_eq!("Service Unavailable.", body);
+        assert_eq!(
+            vec!["loading htpasswd data: reading: unreadable".to_string()],
+            *logged.lock().unwrap()
+        );
+    }
+
+    #[actix_web::test]
+    async fn charset() {
+        let server = actix_test::start(|| {
+            App::new()
+                .wrap(Authenticate::new(passwords()).charset(Charset::Utf8OrLatin1))
+                .route("/private", web::get().to(whoami))
+        });
+        // Clients aren't asked to send UTF-8:
+        let (response, _) = get(&server, None).await;
+        assert_eq!(
+            "Basic realm=\"Restricted\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! # `htpasswd_axum` - Authenticate axum requests against `.htpasswd` files.
+//!
+//! Handlers that take an `HtpasswdUser` parameter only run for
+//! requests with valid HTTP Basic credentials; all others are
+//! answered with `401 Unauthorized` and a challenge for the realm:
+//!
+//! ```rust,ignore
+//! async fn private(user: HtpasswdUser) -> String {
+//!     format!("Hello, {}!", user.username)
+//! }
+//!
+//! let app = Router::new()
+//!     .route("/", get(private))
+//!     .with_state(HtpasswdAuth::new(source).realm("Private"));
+//! ```
+//!
+//! Handlers that also serve anonymous requests take an
+//! `OptionalHtpasswdUser` instead, which is `None` only for requests
+//! without credentials.
+//!
+//! The app state can also be a struct with an `HtpasswdAuth` field,
+//! as long as it implements `FromRef`.
+
+use axum::async_trait;
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::header::{self, HeaderMap};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use htpasswd::{checked_realm, AuthError, BadCredentials, Charset, PasswordDBSource};
+use std::ops::Deref;
+use std::panic;
+use std::sync::Arc;
+use tokio::task;
+
+pub use htpasswd::{basic_challenge, DEFAULT_REALM};
+
+/// Validates the value of an `Authorization` header, decoded in the
+/// given charset, against the current password DB, returning the user
+/// name, or `None` if the DB couldn't be loaded.
+type Validate = Arc<dyn Fn(&[u8], Charset) -> Option<Result<String, AuthError>> + Send + Sync>;
+
+/// The password DB and realm that `HtpasswdUser` authenticates
+/// against, kept in the app state.
+#[derive(Clone)]
+pub struct HtpasswdAuth {
+    validate: Validate,
+    realm: Arc<str>,
+    charset: Charset,
+}
+
+impl HtpasswdAuth {
+    /// Authenticates users against the password DB that `source`
+    /// provides, asking them to log in to `DEFAULT_REALM`.
+    pub fn new<S>(source: S) -> Self
+    where
+        S: PasswordDBSource + Send + Sync + 'static,
+    {
+        HtpasswdAuth {
+            validate: Arc::new(move |header, charset| match source.get().deref() {
+                Ok(db) => Some(db.validate_basic_header_as(header, charset)),
+                Err(_) => None,
+            }),
+            realm: DEFAULT_REALM.into(),
+            charset: Charset::default(),
+        }
+    }
+
+    /// Sets the realm that clients are asked to log in to. Defaults
+    /// to `DEFAULT_REALM`.
+    ///
+    /// # Panics
+    ///
+    /// If the realm isn't printable ASCII (see
+    /// `htpasswd::is_valid_realm`), since it couldn't be sent to
+    /// clients.
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = checked_realm(realm).into();
+        self
+    }
+
+    /// Decodes credentials according to `charset`. Defaults to
+    /// `Charset::Utf8`, which challenges announce to clients; with
+    /// other charsets, they leave the `charset` parameter out.
+    pub fn charset(mut self, charset: Charset) -> Self {
+        self.charset = charset;
+        self
+    }
+
+    /// Authenticates a request with the HTTP Basic credentials in
+    /// its `headers`, validating them on the current thread.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<HtpasswdUser, HtpasswdRejection> {
+        match headers.get(header::AUTHORIZATION) {
+            Some(authorization) => self.check(authorization.as_bytes()),
+            None => Err(self.missing_credentials()),
+        }
+    }
+
+    fn check(&self, authorization: &[u8]) -> Result<HtpasswdUser, HtpasswdRejection> {
+        match (self.validate)(authorization, self.charset) {
+            Some(Ok(username)) => Ok(HtpasswdUser { username }),
+            Some(Err(error)) => Err(HtpasswdRejection::Unauthorized {
+                realm: self.realm.to_string(),
+                charset: self.charset,
+                error,
+            }),
+            None => Err(HtpasswdRejection::LoadError),
+        }
+    }
+
+    fn missing_credentials(&self) -> HtpasswdRejection {
+        HtpasswdRejection::Unauthorized {
+            realm: self.realm.to_string(),
+            charset: self.charset,
+            error: AuthError::NotAuthenticated(BadCredentials::InvalidPassword),
+        }
+    }
+
+    /// Authenticates a request like `authenticate`, but validates the
+    /// credentials on tokio's blocking threads, so that bcrypt doesn't
+    /// hold up the runtime. Returns `None` if the request has no
+    /// credentials.
+    async fn authenticate_blocking(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<Option<HtpasswdUser>, HtpasswdRejection> {
+        let authorization = match headers.get(header::AUTHORIZATION) {
+            Some(authorization) => authorization.as_bytes().to_vec(),
+            None => return Ok(None),
+        };
+        let auth = self.clone();
+        match task::spawn_blocking(move || auth.check(&authorization)).await {
+            Ok(result) => result.map(Some),
+            Err(e) => panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+/// An extractor for the user who logged in with HTTP Basic
+/// credentials.
+///
+/// Don't take an `Option<HtpasswdUser>`: axum turns every rejection
+/// into `None` for it, so wrong credentials and a password DB that
+/// can't be loaded would pass as anonymous requests. Take an
+/// `OptionalHtpasswdUser` instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtpasswdUser {
+    pub username: String,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for HtpasswdUser
+where
+    HtpasswdAuth: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = HtpasswdRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let auth = HtpasswdAuth::from_ref(state);
+        auth.authenticate_blocking(&parts.headers)
+            .await?
+            .ok_or_else(|| auth.missing_credentials())
+    }
+}
+
+/// An extractor for handlers that serve both anonymous requests and
+/// users who logged in: It holds `None` if the request has no
+/// `Authorization` header, and rejects requests with wrong credentials
+/// like `HtpasswdUser` does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionalHtpasswdUser(pub Option<HtpasswdUser>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for OptionalHtpasswdUser
+where
+    HtpasswdAuth: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = HtpasswdRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let auth = HtpasswdAuth::from_ref(state);
+        Ok(OptionalHtpasswdUser(
+            auth.authenticate_blocking(&parts.headers).await?,
+        ))
+    }
+}
+
+/// Why `HtpasswdUser` couldn't be extracted.
+#[derive(Debug)]
+pub enum HtpasswdRejection {
+    /// The credentials are missing or wrong. Responds with `401
+    /// Unauthorized` and a challenge for `realm`, announcing the
+    /// `charset` credentials are decoded in (see `basic_challenge`);
+    /// the body doesn't reveal the `error`.
+    Unauthorized {
+        realm: String,
+        charset: Charset,
+        error: AuthError,
+    },
+
+    /// The password DB couldn't be loaded. Responds with `500
+    /// Internal Server Error`.
+    LoadError,
+}
+
+impl IntoResponse for HtpasswdRejection {
+    fn into_response(self) -> Response {
+        match self {
+            HtpasswdRejection::Unauthorized { realm, charset, .. } => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, basic_challenge(&realm, charset))],
+                "Unauthorized.",
+            )
+                .into_response(),
+            HtpasswdRejection::LoadError => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error.").into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use axum::routing::get;
+    use axum::Router;
+    use htpasswd::{parse_htpasswd_str, LoadFailure, PasswordDB};
+    use std::io;
+    use tower::ServiceExt;
+
+    type Loaded = Arc<Result<PasswordDB, LoadFailure>>;
+
+    fn passwords() -> Loaded {
+        Arc::new(Ok(parse_htpasswd_str(
+            "asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96",
+        )
+        .unwrap()))
+    }
+
+    async fn private(user: HtpasswdUser) -> String {
+        format!("Hello, {}!", user.username)
+    }
+
+    async fn greeting(OptionalHtpasswdUser(user): OptionalHtpasswdUser) -> String {
+        format!("{:?}", user.map(|user| user.username))
+    }
+
+    fn app(auth: HtpasswdAuth) -> Router {
+        Router::new()
+            .route("/private", get(private))
+            .route("/public", get(greeting))
+            .with_state(auth)
+    }
+
+    async fn call(app: Router, path: &str, authorization: Option<&str>) -> Response {
+        let mut request = Request::get(path);
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body(response: Response) -> String {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    // "asf:oink" and "asf:wrong":
+    const CORRECT: &str = "Basic YXNmOm9pbms=";
+    const WRONG: &str = "Basic YXNmOndyb25n";
+
+    #[tokio::test]
+    async fn extracts_users() {
+        let app = app(HtpasswdAuth::new(passwords()).realm("Private"));
+
+        let response = call(app.clone(), "/private", Some(CORRECT)).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("Hello, asf!", body(response).await);
+
+        for authorization in &[None, Some(WRONG)] {
+            let response = call(app.clone(), "/private", *authorization).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+            assert_eq!(
+                "Basic realm=\"Private\", charset=\"UTF-8\"",
+                response.headers()[header::WWW_AUTHENTICATE]
+            );
+        }
+
+        let response = call(app.clone(), "/public", None).await;
+        assert_eq!("None", body(response).await);
+        let response = call(app.clone(), "/public", Some(CORRECT)).await;
+        assert_eq!("Some(\"asf\")", body(response).await);
+        let response = call(app, "/public", Some(WRONG)).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    #[tokio::test]
+    async fn hides_load_errors() {
+        let broken: Loaded = Arc::new(Err(LoadFailure::Io(io::ErrorKind::NotFound.into())));
+        let app = app(HtpasswdAuth::new(broken));
+        for path in &["/private", "/public"] {
+            let response = call(app.clone(), path, Some(CORRECT)).await;
+            assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+            assert_eq!("Internal Server Error.", body(response).await);
+        }
+
+        // Without credentials, there's nothing to check against the DB:
+        let response = call(app, "/public", None).await;
+        assert_eq!("None", body(response).await);
+    }
+
+    #[tokio::test]
+    async fn charset() {
+        let auth = HtpasswdAuth::new(passwords()).charset(Charset::Utf8OrLatin1);
+        let response = call(app(auth), "/private", None).await;
+        // Clients aren't asked to send UTF-8:
+        assert_eq!(
+            "Basic realm=\"Restricted\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+    }
+}
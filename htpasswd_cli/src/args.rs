@@ -0,0 +1,323 @@
+use std::fmt;
+
+pub const USAGE: &str = "Usage:
+\thtpasswd [-ciBDv] [-C cost] passwordfile username
+\thtpasswd -b[cBDv] [-C cost] passwordfile username password
+
+\thtpasswd -n[iB] [-C cost] username
+\thtpasswd -nb[B] [-C cost] username password
+ -c  Create a new file.
+ -n  Don't update file; display results on stdout.
+ -b  Use the password from the command line rather than prompting for it.
+ -i  Read password from stdin without verification (for script usage).
+ -B  Force bcrypt encryption of the password (the default and only choice).
+ -C  Set the computing time used for the bcrypt algorithm
+     (higher is more secure but slower, default: 12, valid: 4 to 31).
+ -D  Delete the specified user.
+ -v  Verify password for the specified user.
+ --  Treat the remaining arguments as file, user name and password, even
+     if they start with `-`.
+
+	htpasswd --audit [options] passwordfile
+ Report weak entries; see `htpasswd --audit --help`.
+
+	htpasswd --check [options] passwordfile...
+ Report every problem in the files; see `htpasswd --check --help`.
+
+ --audit and --check are flags rather than subcommands, so that they can't
+ shadow password files named `audit` or `check`.
+";
+
+/// Returns true if `--help` is among the options in `argv`, i.e.
+/// before any `--` that ends them.
+pub fn asks_for_help(argv: &[String]) -> bool {
+    argv.iter()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg == "--help")
+}
+
+/// The cost that passwords are hashed with unless `-C` is given.
+///
+/// Apache's htpasswd defaults to 5, which is much too cheap nowadays.
+pub const DEFAULT_COST: u32 = 12;
+
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    /// Add the user or change their password.
+    Update,
+
+    /// Remove the user (`-D`).
+    Delete,
+
+    /// Check the user's password (`-v`).
+    Verify,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PasswordSource {
+    /// Ask on the terminal, twice unless verifying.
+    Prompt,
+
+    /// Read a line from stdin (`-i`).
+    Stdin,
+
+    /// Taken from the command line (`-b`).
+    Argument(String),
+}
+
+/// The parsed command line of an htpasswd invocation.
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub mode: Mode,
+
+    /// Start from an empty file instead of updating the existing one
+    /// (`-c`).
+    pub create: bool,
+
+    /// The file to operate on; `None` means the result is printed to
+    /// stdout instead (`-n`).
+    pub file: Option<String>,
+
+    pub user: String,
+    pub password: PasswordSource,
+    pub cost: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ArgsError(String);
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.0)
+    }
+}
+
+fn error<T>(message: &str) -> Result<T, ArgsError> {
+    Err(ArgsError(message.to_string()))
+}
+
+impl Args {
+    /// Parses the arguments (without the program name) the way
+    /// Apache's htpasswd does: All flags come first and may be
+    /// combined, e.g. `-cbB`. A `--` ends the flags, so that a file
+    /// or user name may start with `-`.
+    pub fn parse(argv: &[String]) -> Result<Args, ArgsError> {
+        let mut create = false;
+        let mut to_stdout = false;
+        let mut from_argument = false;
+        let mut from_stdin = false;
+        let mut delete = false;
+        let mut verify = false;
+        let mut cost = DEFAULT_COST;
+
+        let mut rest = argv.iter();
+        let mut positional = vec![];
+        while let Some(arg) = rest.next() {
+            if !arg.starts_with('-') || arg.len() < 2 || !positional.is_empty() {
+                positional.push(arg.clone());
+                continue;
+            }
+            if arg == "--" {
+                positional.extend(rest.by_ref().cloned());
+                break;
+            }
+            for (i, flag) in arg[1..].char_indices() {
+                match flag {
+                    'c' => create = true,
+                    'n' => to_stdout = true,
+                    'b' => from_argument = true,
+                    'i' => from_stdin = true,
+                    'B' => {}
+                    'D' => delete = true,
+                    'v' => verify = true,
+                    'C' => {
+                        let value = match &arg[i + 2..] {
+                            "" => rest.next().map(String::as_str),
+                            attached => Some(attached),
+                        };
+                        cost = match value.map(str::parse) {
+                            Some(Ok(cost)) if (4..=31).contains(&cost) => cost,
+                            Some(_) => return error("argument to -C must be between 4 and 31"),
+                            None => return error("-C requires an argument"),
+                        };
+                        break;
+                    }
+                    'm' | 'd' | 's' | 'p' => {
+                        return Err(ArgsError(format!(
+                            "-{} selects an insecure password format; only bcrypt is supported",
+                            flag
+                        )));
+                    }
+                    _ => return Err(ArgsError(format!("unknown option -{}", flag))),
+                }
+            }
+        }
+
+        if create && to_stdout {
+            return error("-c and -n are mutually exclusive");
+        }
+        if delete && verify {
+            return error("-D and -v are mutually exclusive");
+        }
+        if to_stdout && (delete || verify) {
+            return error("-n can't be combined with -D or -v");
+        }
+        if from_argument && from_stdin {
+            return error("-b and -i are mutually exclusive");
+        }
+        let mode = match (delete, verify) {
+            (true, _) => Mode::Delete,
+            (_, true) => Mode::Verify,
+            _ => Mode::Update,
+        };
+
+        let mut positional = positional.into_iter();
+        let file = if to_stdout {
+            None
+        } else {
+            Some(
+                positional
+                    .next()
+                    .ok_or_else(|| ArgsError("missing password file".to_string()))?,
+            )
+        };
+        let user = positional
+            .next()
+            .ok_or_else(|| ArgsError("missing user name".to_string()))?;
+        let password = match (from_argument, from_stdin) {
+            (true, _) => PasswordSource::Argument(
+                positional
+                    .next()
+                    .ok_or_else(|| ArgsError("missing password".to_string()))?,
+            ),
+            (_, true) => PasswordSource::Stdin,
+            _ => PasswordSource::Prompt,
+        };
+        if positional.next().is_some() {
+            return error("too many arguments");
+        }
+
+        Ok(Args {
+            mode,
+            create,
+            file,
+            user,
+            password,
+            cost,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, ArgsError> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Args::parse(&args)
+    }
+
+    #[test]
+    fn update() {
+        assert_eq!(
+            Ok(Args {
+                mode: Mode::Update,
+                create: false,
+                file: Some("file".to_string()),
+                user: "asf".to_string(),
+                password: PasswordSource::Prompt,
+                cost: DEFAULT_COST,
+            }),
+            parse(&["file", "asf"])
+        );
+        assert_eq!(
+            Ok(Args {
+                mode: Mode::Update,
+                create: true,
+                file: Some("file".to_string()),
+                user: "asf".to_string(),
+                password: PasswordSource::Argument("oink".to_string()),
+                cost: 10,
+            }),
+            parse(&["-cbB", "-C", "10", "file", "asf", "oink"])
+        );
+        assert_eq!(
+            Ok(PasswordSource::Stdin),
+            parse(&["-i", "file", "asf"]).map(|a| a.password)
+        );
+        assert_eq!(Ok(8), parse(&["-BC8", "file", "asf"]).map(|a| a.cost));
+    }
+
+    #[test]
+    fn to_stdout() {
+        assert_eq!(
+            Ok(Args {
+                mode: Mode::Update,
+                create: false,
+                file: None,
+                user: "asf".to_string(),
+                password: PasswordSource::Argument("oink".to_string()),
+                cost: DEFAULT_COST,
+            }),
+            parse(&["-nb", "asf", "oink"])
+        );
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(
+            Ok(Mode::Delete),
+            parse(&["-D", "file", "asf"]).map(|a| a.mode)
+        );
+        assert_eq!(
+            Ok(Mode::Verify),
+            parse(&["-v", "file", "asf"]).map(|a| a.mode)
+        );
+        assert_eq!(
+            Ok(Mode::Verify),
+            parse(&["-vb", "file", "asf", "oink"]).map(|a| a.mode)
+        );
+    }
+
+    #[test]
+    fn end_of_flags() {
+        assert_eq!(
+            Ok((Some("-file".to_string()), "-asf".to_string())),
+            parse(&["-B", "--", "-file", "-asf"]).map(|a| (a.file, a.user))
+        );
+        assert_eq!(
+            Ok(Some("audit".to_string())),
+            parse(&["audit", "asf"]).map(|a| a.file)
+        );
+        assert_eq!(
+            Ok(Some("check".to_string())),
+            parse(&["--", "check", "asf"]).map(|a| a.file)
+        );
+    }
+
+    #[test]
+    fn help() {
+        let argv = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+        assert!(asks_for_help(&argv(&["--help"])));
+        assert!(asks_for_help(&argv(&["--json", "--help", "file"])));
+        assert!(!asks_for_help(&argv(&["file"])));
+        assert!(!asks_for_help(&argv(&["--", "--help"])));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["file"]).is_err());
+        assert!(parse(&["-b", "file", "asf"]).is_err());
+        assert!(parse(&["file", "asf", "oink"]).is_err());
+        assert!(parse(&["-cn", "asf"]).is_err());
+        assert!(parse(&["-Dv", "file", "asf"]).is_err());
+        assert!(parse(&["-nD", "asf"]).is_err());
+        assert!(parse(&["-bi", "file", "asf", "oink"]).is_err());
+        assert!(parse(&["-C", "file", "asf"]).is_err());
+        assert!(parse(&["-C3", "file", "asf"]).is_err());
+        assert!(parse(&["-C"]).is_err());
+        assert!(parse(&["-m", "file", "asf"]).is_err());
+        assert!(parse(&["-x", "file", "asf"]).is_err());
+    }
+}
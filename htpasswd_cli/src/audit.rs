@@ -0,0 +1,175 @@
+use crate::args::asks_for_help;
+use crate::{ERR_FILEPERM, ERR_INVALID, ERR_SYNTAX};
+use htpasswd::{audit_file, AuditPolicy, AuditReport, LoadFailure, Severity};
+
+pub const USAGE: &str = "Usage:
+\thtpasswd --audit [--json] [--min-cost cost] [--fail-on warning|error] passwordfile
+
+Reports users whose passwords are stored insecurely (MD5, SHA1, crypt or
+plaintext) or with a bcrypt cost below the minimum (default: 10), users
+that are listed more than once or have suspicious names, and password
+files that other users can read or write.
+
+ --json     Print the report as JSON.
+ --min-cost Set the lowest acceptable bcrypt cost.
+ --fail-on  Set the severity of findings that cause a non-zero exit
+            (default: warning).
+
+Exit codes: 0 if nothing at or above the --fail-on severity was found,
+11 if something was, 1 if the file can't be read, 7 if it can't be
+parsed and 2 on usage errors.
+";
+
+/// The exit code indicating that the audit turned up problems.
+pub const ERR_FINDINGS: i32 = 11;
+
+#[derive(Debug, PartialEq)]
+pub struct AuditArgs {
+    pub json: bool,
+    pub fail_on: Severity,
+    pub policy: AuditPolicy,
+    pub file: String,
+}
+
+impl AuditArgs {
+    pub fn parse(argv: &[String]) -> Result<AuditArgs, String> {
+        let mut json = false;
+        let mut fail_on = Severity::Warning;
+        let mut policy = AuditPolicy::default();
+        let mut files = vec![];
+
+        let mut rest = argv.iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--json" => json = true,
+                "--min-cost" => {
+                    policy.min_bcrypt_cost = rest
+                        .next()
+                        .and_then(|cost| cost.parse().ok())
+                        .ok_or("--min-cost requires a number")?;
+                }
+                "--fail-on" => {
+                    fail_on = match rest.next().map(String::as_str) {
+                        Some("warning") => Severity::Warning,
+                        Some("error") => Severity::Error,
+                        _ => return Err("--fail-on must be `warning` or `error`".to_string()),
+                    };
+                }
+                "--" => {
+                    files.extend(rest.by_ref().cloned());
+                    break;
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                _ => files.push(arg.clone()),
+            }
+        }
+        if files.len() > 1 {
+            return Err("too many arguments".to_string());
+        }
+        Ok(AuditArgs {
+            json,
+            fail_on,
+            policy,
+            file: files.pop().ok_or("missing password file")?,
+        })
+    }
+}
+
+fn print_report(report: &AuditReport, file: &str, json: bool) {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(report).expect("reports serialize")
+        );
+    } else {
+        for finding in &report.findings {
+            println!("{}: {}", file, finding);
+        }
+    }
+}
+
+/// Runs `htpasswd --audit` and returns the exit code.
+pub fn main(argv: &[String]) -> i32 {
+    if asks_for_help(argv) {
+        print!("{}", USAGE);
+        return 0;
+    }
+    let args = match AuditArgs::parse(argv) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("htpasswd --audit: {}", e);
+            eprint!("{}", USAGE);
+            return ERR_SYNTAX;
+        }
+    };
+    match audit_file(&args.file, &args.policy) {
+        Ok(report) => {
+            print_report(&report, &args.file, args.json);
+            match report.worst() {
+                Some(worst) if worst >= args.fail_on => ERR_FINDINGS,
+                _ => 0,
+            }
+        }
+        Err(e) => {
+            eprintln!("htpasswd --audit: {}: {}", args.file, e);
+            match e {
+                LoadFailure::Io(_) => ERR_FILEPERM,
+                LoadFailure::Parse(_) => ERR_INVALID,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<AuditArgs, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        AuditArgs::parse(&args)
+    }
+
+    #[test]
+    fn args() {
+        assert_eq!(
+            Ok(AuditArgs {
+                json: false,
+                fail_on: Severity::Warning,
+                policy: AuditPolicy::default(),
+                file: "file".to_string(),
+            }),
+            parse(&["file"])
+        );
+        assert_eq!(
+            Ok(AuditArgs {
+                json: true,
+                fail_on: Severity::Error,
+                policy: AuditPolicy {
+                    min_bcrypt_cost: 12,
+                    ..AuditPolicy::default()
+                },
+                file: "file".to_string(),
+            }),
+            parse(&["--json", "--min-cost", "12", "file", "--fail-on", "error"])
+        );
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["file", "other"]).is_err());
+        assert!(parse(&["--min-cost", "many", "file"]).is_err());
+        assert!(parse(&["--fail-on", "info", "file"]).is_err());
+        assert!(parse(&["--verbose", "file"]).is_err());
+    }
+
+    #[test]
+    fn end_of_flags() {
+        assert_eq!(
+            Ok("--json".to_string()),
+            parse(&["--", "--json"]).map(|a| a.file)
+        );
+        assert_eq!(
+            Ok((true, "--help".to_string())),
+            parse(&["--json", "--", "--help"]).map(|a| (a.json, a.file))
+        );
+        assert!(parse(&["--"]).is_err());
+        assert!(parse(&["file", "--", "other"]).is_err());
+    }
+}
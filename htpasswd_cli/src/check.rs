@@ -0,0 +1,194 @@
+use crate::args::asks_for_help;
+use crate::audit::ERR_FINDINGS;
+use crate::{ERR_FILEPERM, ERR_SYNTAX};
+use htpasswd::{check_htpasswd_bytes, AuditPolicy, Diagnostic, Severity};
+use std::fs;
+
+pub const USAGE: &str = "Usage:
+\thtpasswd --check [--min-cost cost] [--fail-on warning|error] passwordfile...
+
+Checks password files for syntax errors, CRLF line endings, users that are
+listed more than once or have suspicious names, and passwords that are
+stored insecurely (MD5, SHA1, crypt or plaintext) or with a bcrypt cost
+below the minimum (default: 10). Every problem in every file is reported,
+with the line it was found on. Files that aren't UTF-8 are read as Latin-1.
+
+ --min-cost Set the lowest acceptable bcrypt cost.
+ --fail-on  Set the severity of problems that cause a non-zero exit
+            (default: error).
+
+Exit codes: 0 if nothing at or above the --fail-on severity was found,
+11 if something was, 1 if a file can't be read (the others are still
+checked) and 2 on usage errors.
+";
+
+#[derive(Debug, PartialEq)]
+pub struct CheckArgs {
+    pub fail_on: Severity,
+    pub policy: AuditPolicy,
+    pub files: Vec<String>,
+}
+
+impl CheckArgs {
+    pub fn parse(argv: &[String]) -> Result<CheckArgs, String> {
+        let mut fail_on = Severity::Error;
+        let mut policy = AuditPolicy::default();
+        let mut files = vec![];
+
+        let mut rest = argv.iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--min-cost" => {
+                    policy.min_bcrypt_cost = rest
+                        .next()
+                        .and_then(|cost| cost.parse().ok())
+                        .ok_or("--min-cost requires a number")?;
+                }
+                "--fail-on" => {
+                    fail_on = match rest.next().map(String::as_str) {
+                        Some("warning") => Severity::Warning,
+                        Some("error") => Severity::Error,
+                        _ => return Err("--fail-on must be `warning` or `error`".to_string()),
+                    };
+                }
+                "--" => {
+                    files.extend(rest.by_ref().cloned());
+                    break;
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                _ => files.push(arg.clone()),
+            }
+        }
+        if files.is_empty() {
+            return Err("missing password file".to_string());
+        }
+        Ok(CheckArgs {
+            fail_on,
+            policy,
+            files,
+        })
+    }
+}
+
+/// Renders a diagnostic with the line it refers to, pointing out the
+/// column.
+fn render(file: &str, contents: &str, diagnostic: &Diagnostic) -> String {
+    let source = contents
+        .lines()
+        .nth(diagnostic.line as usize - 1)
+        .unwrap_or("");
+    let prefix_len = diagnostic.column.saturating_sub(1);
+    let indent = source
+        .get(..prefix_len)
+        .map_or(prefix_len, |prefix| prefix.chars().count());
+    let gutter = " ".repeat(diagnostic.line.to_string().len());
+    format!(
+        "{}:{}:{}: {}\n{} |\n{} | {}\n{} | {}^\n",
+        file,
+        diagnostic.line,
+        diagnostic.column,
+        diagnostic,
+        gutter,
+        diagnostic.line,
+        source,
+        gutter,
+        " ".repeat(indent)
+    )
+}
+
+/// Runs `htpasswd --check` and returns the exit code.
+pub fn main(argv: &[String]) -> i32 {
+    if asks_for_help(argv) {
+        print!("{}", USAGE);
+        return 0;
+    }
+    let args = match CheckArgs::parse(argv) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("htpasswd --check: {}", e);
+            eprint!("{}", USAGE);
+            return ERR_SYNTAX;
+        }
+    };
+
+    let mut worst = None;
+    let mut unreadable = false;
+    for file in &args.files {
+        let contents = match fs::read(file) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("htpasswd --check: {}: {}", file, e);
+                unreadable = true;
+                continue;
+            }
+        };
+        let (text, diagnostics) = check_htpasswd_bytes(&contents, &args.policy);
+        for diagnostic in diagnostics {
+            print!("{}", render(file, &text, &diagnostic));
+            worst = worst.max(Some(diagnostic.severity));
+        }
+    }
+    match worst {
+        _ if unreadable => ERR_FILEPERM,
+        Some(worst) if worst >= args.fail_on => ERR_FINDINGS,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use htpasswd::check_htpasswd_str;
+
+    fn parse(args: &[&str]) -> Result<CheckArgs, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        CheckArgs::parse(&args)
+    }
+
+    #[test]
+    fn args() {
+        assert_eq!(
+            Ok(CheckArgs {
+                fail_on: Severity::Warning,
+                policy: AuditPolicy {
+                    min_bcrypt_cost: 12,
+                    ..AuditPolicy::default()
+                },
+                files: vec!["one".to_string(), "two".to_string()],
+            }),
+            parse(&["--min-cost", "12", "one", "--fail-on", "warning", "two"])
+        );
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--fail-on", "error"]).is_err());
+        assert!(parse(&["--json", "file"]).is_err());
+    }
+
+    #[test]
+    fn end_of_flags() {
+        assert_eq!(
+            Ok(vec![
+                "one".to_string(),
+                "--help".to_string(),
+                "--".to_string()
+            ]),
+            parse(&["one", "--", "--help", "--"]).map(|a| a.files)
+        );
+        assert!(parse(&["--"]).is_err());
+    }
+
+    #[test]
+    fn rendering() {
+        let contents = "asf:$2y$10$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96
+bsf:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=
+";
+        let diagnostics = check_htpasswd_str(contents, &AuditPolicy::default());
+        assert_eq!(
+            "htpasswd:2:5: error: user \"bsf\": password is stored with insecure SHA1
+  |
+2 | bsf:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=
+  |     ^
+",
+            render("htpasswd", contents, &diagnostics[0])
+        );
+    }
+}
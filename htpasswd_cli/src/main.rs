@@ -0,0 +1,210 @@
+//! # `htpasswd` - Manage `.htpasswd` files.
+//!
+//! A replacement for the `htpasswd` tool that ships with the Apache
+//! web server, built on the `htpasswd` crate. It supports the same
+//! command line flags as Apache's tool, except that passwords are
+//! only ever hashed with bcrypt. Files are updated atomically, under
+//! an advisory lock.
+//!
+//! Additionally, `htpasswd --audit` reports weaknesses in a password
+//! file, and `htpasswd --check` lints password files, e.g. before they
+//! are deployed. These are flags rather than subcommands so that they
+//! can't be mistaken for a password file named `audit` or `check`.
+
+use htpasswd::{
+    AuthError, BadCredentials, HtpasswdLoad, LoadFailure, LockedFile, PasswordDB, UpdateError,
+};
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::process;
+
+mod args;
+mod audit;
+mod check;
+
+use crate::args::{Args, Mode, PasswordSource};
+
+// Exit codes, as used by Apache's htpasswd:
+const ERR_FILEPERM: i32 = 1;
+const ERR_SYNTAX: i32 = 2;
+const ERR_PWMISMATCH: i32 = 3;
+const ERR_INTERRUPTED: i32 = 4;
+const ERR_OVERFLOW: i32 = 5;
+const ERR_BADUSER: i32 = 6;
+const ERR_INVALID: i32 = 7;
+
+/// Something that makes the program exit unsuccessfully.
+struct Failure {
+    code: i32,
+    message: String,
+}
+
+impl Failure {
+    fn new<D: fmt::Display>(code: i32, message: D) -> Failure {
+        Failure {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<LoadFailure> for Failure {
+    fn from(f: LoadFailure) -> Self {
+        match f {
+            LoadFailure::Io(_) => Failure::new(ERR_FILEPERM, f),
+            LoadFailure::Parse(_) => Failure::new(ERR_INVALID, f),
+        }
+    }
+}
+
+impl From<UpdateError> for Failure {
+    fn from(f: UpdateError) -> Self {
+        match f {
+            UpdateError::BadUsername => Failure::new(ERR_BADUSER, f),
+            UpdateError::Hash(AuthError::NotAuthenticated(BadCredentials::PasswordTooLong)) => {
+                Failure::new(ERR_OVERFLOW, f)
+            }
+            UpdateError::Hash(_) => Failure::new(ERR_INTERRUPTED, f),
+        }
+    }
+}
+
+/// Reads a file without locking it, since it won't be modified.
+fn load(file: &str) -> Result<PasswordDB, LoadFailure> {
+    let mut f = File::open(file)?;
+    if f.metadata()?.len() == 0 {
+        return Ok(PasswordDB::default());
+    }
+    f.load_htpasswd()
+}
+
+fn file_error(file: &str, e: io::Error) -> Failure {
+    Failure::new(ERR_FILEPERM, format!("cannot modify file {}: {}", file, e))
+}
+
+/// Obtains the password from wherever the command line says to.
+fn read_password(source: &PasswordSource, confirm: bool) -> Result<String, Failure> {
+    let interrupted =
+        |e: io::Error| Failure::new(ERR_INTERRUPTED, format!("reading password: {}", e));
+    match source {
+        PasswordSource::Argument(password) => Ok(password.clone()),
+        PasswordSource::Stdin => {
+            let mut line = String::new();
+            io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .map_err(interrupted)?;
+            Ok(line
+                .trim_end_matches(|c| c == '\n' || c == '\r')
+                .to_string())
+        }
+        PasswordSource::Prompt if !confirm => {
+            rpassword::read_password_from_tty(Some("Enter password: ")).map_err(interrupted)
+        }
+        PasswordSource::Prompt => {
+            let password =
+                rpassword::read_password_from_tty(Some("New password: ")).map_err(interrupted)?;
+            let again = rpassword::read_password_from_tty(Some("Re-type new password: "))
+                .map_err(interrupted)?;
+            if password != again {
+                return Err(Failure::new(ERR_PWMISMATCH, "password verification error"));
+            }
+            Ok(password)
+        }
+    }
+}
+
+fn print_entry(args: &Args) -> Result<(), Failure> {
+    let password = read_password(&args.password, true)?;
+    let mut db = PasswordDB::default();
+    db.set_password(&args.user, &password, args.cost)?;
+    print!("{}", db);
+    Ok(())
+}
+
+fn update(file: &str, args: &Args) -> Result<(), Failure> {
+    let password = read_password(&args.password, true)?;
+    let (locked, mut db) = if args.create {
+        let locked = LockedFile::create(file).map_err(|e| file_error(file, e))?;
+        (locked, PasswordDB::default())
+    } else {
+        let mut locked = LockedFile::lock(file).map_err(|e| file_error(file, e))?;
+        let db = locked.load()?;
+        (locked, db)
+    };
+    let verb = if db.contains_user(&args.user) {
+        "Updating"
+    } else {
+        "Adding"
+    };
+    db.set_password(&args.user, &password, args.cost)?;
+    locked.save(&db).map_err(|e| file_error(file, e))?;
+    eprintln!("{} password for user {}", verb, args.user);
+    Ok(())
+}
+
+fn delete(file: &str, args: &Args) -> Result<(), Failure> {
+    let mut locked = LockedFile::lock(file).map_err(|e| file_error(file, e))?;
+    let mut db = locked.load()?;
+    if !db.remove(&args.user) {
+        eprintln!("User {} not found", args.user);
+        return Ok(());
+    }
+    locked.save(&db).map_err(|e| file_error(file, e))?;
+    eprintln!("Deleting password for user {}", args.user);
+    Ok(())
+}
+
+fn verify(file: &str, args: &Args) -> Result<(), Failure> {
+    let db = load(file)?;
+    if !db.contains_user(&args.user) {
+        return Err(Failure::new(
+            ERR_BADUSER,
+            format!("User {} not found", args.user),
+        ));
+    }
+    let password = read_password(&args.password, false)?;
+    match db.validate(&args.user, &password) {
+        Ok(()) => {
+            eprintln!("Password for user {} correct.", args.user);
+            Ok(())
+        }
+        Err(AuthError::NotAuthenticated(BadCredentials::InsecureStorage)) => Err(Failure::new(
+            ERR_PWMISMATCH,
+            "password is stored in an insecure format and can't be verified",
+        )),
+        Err(_) => Err(Failure::new(ERR_PWMISMATCH, "password verification failed")),
+    }
+}
+
+fn run(args: &Args) -> Result<(), Failure> {
+    match (&args.mode, &args.file) {
+        (_, None) => print_entry(args),
+        (Mode::Update, Some(file)) => update(file, args),
+        (Mode::Delete, Some(file)) => delete(file, args),
+        (Mode::Verify, Some(file)) => verify(file, args),
+    }
+}
+
+fn main() {
+    let argv: Vec<String> = env::args().skip(1).collect();
+    match argv.first().map(String::as_str) {
+        Some("--audit") => process::exit(audit::main(&argv[1..])),
+        Some("--check") => process::exit(check::main(&argv[1..])),
+        _ => {}
+    }
+    let args = match Args::parse(&argv) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("htpasswd: {}", e);
+            eprint!("{}", args::USAGE);
+            process::exit(ERR_SYNTAX);
+        }
+    };
+    if let Err(failure) = run(&args) {
+        eprintln!("htpasswd: {}", failure.message);
+        process::exit(failure.code);
+    }
+}
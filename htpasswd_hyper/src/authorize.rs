@@ -0,0 +1,264 @@
+use crate::{
+    basic_challenge, group_lookup, reject, AuthenticatedUser, GroupLookup, Party, Rejection,
+    ResponseFuture, ResponseHook, Rules, DEFAULT_REALM,
+};
+use htpasswd::{checked_realm, Charset, GroupDBSource};
+use hyper::{service::Service, Body, Request, Response, StatusCode};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// An Apache-style `Require` directive, deciding which users may
+/// access a resource.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Require {
+    /// `Require all granted`: everybody, even anonymous users.
+    AllGranted,
+
+    /// `Require all denied`: nobody.
+    AllDenied,
+
+    /// `Require valid-user`: any authenticated user.
+    ValidUser,
+
+    /// `Require user alice bob`: the listed users.
+    User(Vec<String>),
+
+    /// `Require group admins`: members of any of the listed groups.
+    Group(Vec<String>),
+
+    /// Satisfied if any of the requirements is, like several
+    /// `Require` lines in a `<RequireAny>` block.
+    Any(Vec<Require>),
+}
+
+impl Require {
+    /// Returns whether the requirement is met by `user` (`None` for
+    /// anonymous requests), who is a member of `groups`.
+    pub fn is_satisfied(&self, user: Option<&str>, groups: &[String]) -> bool {
+        use Require::*;
+        match (self, user) {
+            (AllGranted, _) => true,
+            (Any(requirements), user) => requirements
+                .iter()
+                .any(|requirement| requirement.is_satisfied(user, groups)),
+            (AllDenied, _) | (_, None) => false,
+            (ValidUser, Some(_)) => true,
+            (User(users), Some(user)) => users.iter().any(|u| u == user),
+            (Group(required), Some(_)) => required.iter().any(|g| groups.contains(g)),
+        }
+    }
+}
+
+/// Indicates that a `Require` directive couldn't be parsed.
+#[derive(Debug, PartialEq)]
+pub struct ParseRequireError(String);
+
+impl fmt::Display for ParseRequireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "unsupported Require directive: {:?}", self.0)
+    }
+}
+
+impl Error for ParseRequireError {}
+
+impl FromStr for Require {
+    type Err = ParseRequireError;
+
+    /// Parses a directive as written in Apache's configuration, with
+    /// or without the leading `Require`, e.g. `user alice bob`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace().peekable();
+        if words
+            .peek()
+            .map_or(false, |w| w.eq_ignore_ascii_case("Require"))
+        {
+            words.next();
+        }
+        let kind = words.next().unwrap_or("");
+        let names: Vec<String> = words.map(str::to_string).collect();
+        match (kind, names.len()) {
+            ("valid-user", 0) => Ok(Require::ValidUser),
+            ("user", n) if n > 0 => Ok(Require::User(names)),
+            ("group", n) if n > 0 => Ok(Require::Group(names)),
+            ("all", 1) if names[0] == "granted" => Ok(Require::AllGranted),
+            ("all", 1) if names[0] == "denied" => Ok(Require::AllDenied),
+            _ => Err(ParseRequireError(s.to_string())),
+        }
+    }
+}
+
+/// Checks that the user who was authenticated by `Authenticate` (or
+/// `AuthenticateDigest`) meets the `Require` directive for the
+/// request, as decided by the first of its rules that matches.
+///
+/// Requests that need a user but have none are answered with `401
+/// Unauthorized` (`407 Proxy Authentication Required` in proxy mode);
+/// requests by users that don't meet the requirement get `403
+/// Forbidden`.
+pub struct Authorize<T>
+where
+    T: Service<ResBody = Body>,
+{
+    upstream: T,
+    rules: Arc<Rules<Require>>,
+    realm: String,
+    charset: Charset,
+    groups: Option<GroupLookup>,
+    party: Party,
+    response_hook: Option<ResponseHook<T::ReqBody>>,
+}
+
+impl<T> Authorize<T>
+where
+    T: Service<ResBody = Body>,
+{
+    pub fn new(upstream: T, rules: Arc<Rules<Require>>) -> Self {
+        Authorize {
+            upstream,
+            rules,
+            realm: DEFAULT_REALM.to_string(),
+            charset: Charset::default(),
+            groups: None,
+            party: Party::Origin,
+            response_hook: None,
+        }
+    }
+
+    /// Sets the realm that anonymous clients are challenged to log in
+    /// to, which should be the one that `Authenticate` uses.
+    ///
+    /// # Panics
+    ///
+    /// If the realm isn't printable ASCII (see
+    /// `htpasswd::is_valid_realm`).
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = checked_realm(realm);
+        self
+    }
+
+    /// Sets the charset that challenges announce (or don't), which
+    /// should be the one that `Authenticate` decodes credentials in.
+    pub fn charset(mut self, charset: Charset) -> Self {
+        self.charset = charset;
+        self
+    }
+
+    /// Looks up group memberships in `groups` for users whose
+    /// `AuthenticatedUser` doesn't list them.
+    pub fn groups<G>(mut self, groups: G) -> Self
+    where
+        G: GroupDBSource + Send + Sync + 'static,
+    {
+        self.groups = Some(group_lookup(groups));
+        self
+    }
+
+    /// Challenges anonymous clients on behalf of a proxy, with `407
+    /// Proxy Authentication Required` and a `Proxy-Authenticate`
+    /// header, for use behind an `Authenticate` in proxy mode.
+    pub fn proxy(mut self) -> Self {
+        self.party = Party::Proxy;
+        self
+    }
+
+    /// Builds the responses to rejected requests with `hook`, like
+    /// `Authenticate::respond_with`.
+    pub fn respond_with<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Request<T::ReqBody>, &Rejection) -> Response<Body> + Send + Sync + 'static,
+    {
+        self.response_hook = Some(Arc::new(hook));
+        self
+    }
+}
+
+impl<T> Service for Authorize<T>
+where
+    T: Service<ResBody = Body>,
+{
+    type ReqBody = T::ReqBody;
+    type ResBody = Body;
+    type Error = T::Error;
+    type Future = ResponseFuture<T::Future>;
+
+    fn call(&mut self, request: Request<Self::ReqBody>) -> Self::Future {
+        let user = request.extensions().get::<AuthenticatedUser>();
+        let groups = match user {
+            Some(AuthenticatedUser {
+                groups: Some(groups),
+                ..
+            }) => groups.clone(),
+            Some(user) => self
+                .groups
+                .as_ref()
+                .and_then(|lookup| lookup(&user.username))
+                .unwrap_or_default(),
+            None => vec![],
+        };
+        let username = user.map(|user| user.username.as_str());
+        let allowed = self.rules.action(&request).is_satisfied(username, &groups);
+        let authenticated = username.is_some();
+        if allowed {
+            return ResponseFuture::upstream(self.upstream.call(request));
+        }
+        let rejection = Rejection {
+            status: if authenticated {
+                StatusCode::FORBIDDEN
+            } else {
+                self.party.unauthorized()
+            },
+            error: None,
+        };
+        ResponseFuture::rejected(reject(
+            &request,
+            &rejection,
+            &self.response_hook,
+            Some((
+                self.party.challenge_header(),
+                basic_challenge(&self.realm, self.charset),
+            )),
+            None,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(Require::ValidUser), "Require valid-user".parse());
+        assert_eq!(
+            Ok(Require::User(vec!["alice".to_string(), "bob".to_string()])),
+            "user alice  bob".parse()
+        );
+        assert_eq!(
+            Ok(Require::Group(vec!["admins".to_string()])),
+            "require group admins".parse()
+        );
+        assert_eq!(Ok(Require::AllDenied), "Require all denied".parse());
+        assert!("Require user".parse::<Require>().is_err());
+        assert!("Require ip 10.0.0.0/8".parse::<Require>().is_err());
+    }
+
+    #[test]
+    fn satisfaction() {
+        let admins = vec!["admins".to_string()];
+        let alice: Require = "user alice".parse().unwrap();
+        let group: Require = "group admins".parse().unwrap();
+
+        assert!(Require::AllGranted.is_satisfied(None, &[]));
+        assert!(!Require::ValidUser.is_satisfied(None, &[]));
+        assert!(Require::ValidUser.is_satisfied(Some("bob"), &[]));
+        assert!(alice.is_satisfied(Some("alice"), &[]));
+        assert!(!alice.is_satisfied(Some("bob"), &admins));
+        assert!(group.is_satisfied(Some("bob"), &admins));
+        assert!(!group.is_satisfied(Some("alice"), &[]));
+        assert!(Require::Any(vec![alice, group]).is_satisfied(Some("bob"), &admins));
+        assert!(Require::Any(vec![Require::AllGranted]).is_satisfied(None, &[]));
+        assert!(!Require::AllDenied.is_satisfied(Some("alice"), &admins));
+    }
+}
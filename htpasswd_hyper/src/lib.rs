@@ -1,74 +1,1510 @@
+use hyper::header::{self, HeaderName, HeaderValue};
 use hyper::{service::Service, Body, Request, Response, StatusCode};
+use std::error::Error;
+use std::net::IpAddr;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures::future::FutureResult;
-use headers::{authorization::Basic, Authorization, HeaderMapExt};
-use htpasswd::{AuthError, BadCredentials, PasswordDB, PasswordDBSource};
+use futures::future::Either;
+use futures::{Async, Future, Poll};
+use htpasswd::{
+    checked_realm, AuthError, BadCredentials, Charset, CredentialCache, DigestCredentials,
+    DigestDB, DigestDBSource, GroupDBSource, Nonces, PasswordDB, PasswordDBSource, Throttle,
+    ValidationFuture, ValidationPool,
+};
+
+mod authorize;
+mod rules;
+
+pub use authorize::{Authorize, ParseRequireError, Require};
+pub use htpasswd::{basic_challenge, AuthenticatedUser, DEFAULT_REALM};
+pub use rules::{Access, Rule, Rules};
+
+/// Returns a `Retry-After` value in seconds, rounded up so that
+/// clients don't retry too early.
+fn retry_after_seconds(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+/// Receives the errors that occur when loading the password DB.
+type ErrorHook = Arc<dyn Fn(&dyn Error) + Send + Sync>;
+
+/// How a service responds when its DB can't be loaded. Clients only
+/// ever get a generic response; the details go to the hook, if any.
+struct LoadErrorHandling {
+    hook: Option<ErrorHook>,
+    status: StatusCode,
+    retry_after: Option<Duration>,
+}
+
+impl Default for LoadErrorHandling {
+    fn default() -> Self {
+        LoadErrorHandling {
+            hook: None,
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            retry_after: None,
+        }
+    }
+}
+
+impl LoadErrorHandling {
+    fn report(&self, error: &dyn Error) {
+        if let Some(hook) = &self.hook {
+            hook(error);
+        }
+    }
+}
+
+/// Why a request wasn't passed upstream, as described to a response
+/// hook.
+#[derive(Debug)]
+pub struct Rejection<'a> {
+    /// The status that the response should have: `401
+    /// Unauthorized` (`407 Proxy Authentication Required` in proxy
+    /// mode), `403 Forbidden` if access is denied, `429
+    /// Too Many Requests` if the client was throttled, `503 Service
+    /// Unavailable` if the validation pool is overloaded, or a 5xx
+    /// status if the DB couldn't be loaded.
+    pub status: StatusCode,
+
+    /// Why the credentials weren't accepted, or `None` if they
+    /// weren't the problem: because the request was denied by a rule
+    /// or the user doesn't meet a `Require` directive (with `403
+    /// Forbidden`), `Authorize` got a request without a user, or the
+    /// DB couldn't be loaded. Like `AuthError`'s
+    /// `Display` implementation, responses should not reveal which
+    /// of the `BadCredentials` it was.
+    pub error: Option<&'a AuthError>,
+}
+
+/// Builds the responses to rejected requests.
+type ResponseHook<B> = Arc<dyn Fn(&Request<B>, &Rejection) -> Response<Body> + Send + Sync>;
+
+/// The response to a rejected request unless a response hook is
+/// configured: just the status, with a body that doesn't tell the
+/// client anything more.
+pub fn default_response(rejection: &Rejection) -> Response<Body> {
+    let body = match rejection.status {
+        StatusCode::TOO_MANY_REQUESTS => "Too many failed attempts.".to_string(),
+        status => format!("{}.", status.canonical_reason().unwrap_or("Error")),
+    };
+    Response::builder()
+        .status(rejection.status)
+        .body(Body::from(body))
+        .expect("Response should build")
+}
+
+/// A response that is still waiting for credentials to be validated.
+type Deferred<E> = Box<dyn Future<Item = Response<Body>, Error = E> + Send>;
+
+/// The future returned by the services in this crate: either the
+/// upstream service's response, or the response to a rejected
+/// request.
+pub struct ResponseFuture<F>
+where
+    F: Future<Item = Response<Body>>,
+{
+    state: ResponseState<F>,
+}
+
+enum ResponseState<F>
+where
+    F: Future<Item = Response<Body>>,
+{
+    Upstream(F),
+    Rejected(Option<Response<Body>>),
+    Deferred(Deferred<F::Error>),
+}
+
+impl<F> ResponseFuture<F>
+where
+    F: Future<Item = Response<Body>>,
+{
+    fn upstream(future: F) -> Self {
+        ResponseFuture {
+            state: ResponseState::Upstream(future),
+        }
+    }
+
+    fn rejected(response: Response<Body>) -> Self {
+        ResponseFuture {
+            state: ResponseState::Rejected(Some(response)),
+        }
+    }
+
+    fn deferred(future: Deferred<F::Error>) -> Self {
+        ResponseFuture {
+            state: ResponseState::Deferred(future),
+        }
+    }
+}
+
+impl<F> Future for ResponseFuture<F>
+where
+    F: Future<Item = Response<Body>>,
+{
+    type Item = Response<Body>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match &mut self.state {
+            ResponseState::Upstream(future) => future.poll(),
+            ResponseState::Rejected(response) => Ok(Async::Ready(
+                response.take().expect("polled a completed ResponseFuture"),
+            )),
+            ResponseState::Deferred(future) => future.poll(),
+        }
+    }
+}
+
+/// Responds to a rejected request, making sure that the response has
+/// the headers that clients need to log in or retry. The `challenge`
+/// is the header asking for credentials and its value.
+fn reject<B>(
+    request: &Request<B>,
+    rejection: &Rejection,
+    hook: &Option<ResponseHook<B>>,
+    challenge: Option<(HeaderName, String)>,
+    retry_after: Option<Duration>,
+) -> Response<Body> {
+    let mut response = match hook {
+        Some(hook) => hook(request, rejection),
+        None => default_response(rejection),
+    };
+    let unauthorized = response.status() == StatusCode::UNAUTHORIZED
+        || response.status() == StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    let headers = response.headers_mut();
+    if let Some((name, challenge)) = challenge {
+        if unauthorized && !headers.contains_key(&name) {
+            if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                headers.insert(name, challenge);
+            }
+        }
+    }
+    if let Some(retry_after) = retry_after {
+        if !headers.contains_key(header::RETRY_AFTER) {
+            headers.insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after_seconds(retry_after)),
+            );
+        }
+    }
+    response
+}
+
+/// Looks up the groups a user is a member of.
+type GroupLookup = Arc<dyn Fn(&str) -> Option<Vec<String>> + Send + Sync>;
+
+fn group_lookup<G>(groups: G) -> GroupLookup
+where
+    G: GroupDBSource + Send + Sync + 'static,
+{
+    Arc::new(move |user| match groups.get().deref() {
+        Ok(db) => Some(db.groups_of(user).into_iter().map(String::from).collect()),
+        Err(_) => None,
+    })
+}
+
+/// Prepares an authenticated request for the upstream service,
+/// removing the credentials that were sent to `party` if
+/// `strip_authorization` is set.
+fn forward_as<B>(
+    request: &mut Request<B>,
+    username: String,
+    groups: &Option<GroupLookup>,
+    party: Party,
+    strip_authorization: bool,
+) {
+    let groups = groups.as_ref().and_then(|lookup| lookup(&username));
+    request
+        .extensions_mut()
+        .insert(AuthenticatedUser { username, groups });
+    if strip_authorization {
+        request.headers_mut().remove(party.credentials_header());
+    }
+}
+
+/// Whether clients authenticate to the server they talk to, or to a
+/// proxy in between.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Party {
+    Origin,
+    Proxy,
+}
+
+impl Party {
+    /// The header that carries the client's credentials.
+    pub(crate) fn credentials_header(self) -> HeaderName {
+        match self {
+            Party::Origin => header::AUTHORIZATION,
+            Party::Proxy => header::PROXY_AUTHORIZATION,
+        }
+    }
+
+    /// The header that asks the client for credentials.
+    pub(crate) fn challenge_header(self) -> HeaderName {
+        match self {
+            Party::Origin => header::WWW_AUTHENTICATE,
+            Party::Proxy => header::PROXY_AUTHENTICATE,
+        }
+    }
+
+    /// The status of responses to requests without valid credentials.
+    pub(crate) fn unauthorized(self) -> StatusCode {
+        match self {
+            Party::Origin => StatusCode::UNAUTHORIZED,
+            Party::Proxy => StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+        }
+    }
+}
+
+/// Returns the value of the header with the credentials sent with a
+/// request to `party`.
+fn credentials<T>(req: &Request<T>, party: Party) -> Result<&HeaderValue, AuthError> {
+    req.headers()
+        .get(party.credentials_header())
+        .ok_or(AuthError::NotAuthenticated(BadCredentials::InvalidPassword))
+}
+
+/// Returns the HTTP Basic credentials sent with a request to `party`,
+/// decoded according to `charset`.
+fn basic_credentials<T>(
+    req: &Request<T>,
+    db: &PasswordDB,
+    party: Party,
+    charset: Charset,
+) -> Result<(String, String), AuthError> {
+    let header = credentials(req, party)?;
+    Ok(db.decode_basic_header_as(header.as_bytes(), charset)?)
+}
+
+fn basic_auth<T>(
+    req: &Request<T>,
+    db: &PasswordDB,
+    party: Party,
+    charset: Charset,
+) -> Result<(), AuthError> {
+    let header = credentials(req, party)?;
+    db.validate_basic_header_as(header.as_bytes(), charset)
+        .map(|_user| ())
+}
 
 /// Authenticates a request to the server using the HTTP Basic
 /// Authorization protocol against a password DB loaded from a
-/// .htpasswd.
+/// .htpasswd. The credentials must be encoded in UTF-8.
 pub fn basic_auth_via_htpasswd<T>(req: &Request<T>, db: &PasswordDB) -> Result<(), AuthError> {
-    match req.headers().typed_get::<Authorization<Basic>>() {
-        None => Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword)),
-        Some(auth) => db.validate(auth.0.username(), auth.0.password()),
+    basic_auth(req, db, Party::Origin, Charset::Utf8)
+}
+
+/// Like `basic_auth_via_htpasswd`, but decodes the credentials
+/// according to `charset`, e.g. to accept the Latin-1 credentials of
+/// older clients.
+pub fn basic_auth_via_htpasswd_as<T>(
+    req: &Request<T>,
+    db: &PasswordDB,
+    charset: Charset,
+) -> Result<(), AuthError> {
+    basic_auth(req, db, Party::Origin, charset)
+}
+
+/// Like `basic_auth_via_htpasswd`, but for a request to a proxy,
+/// using the credentials in its `Proxy-Authorization` header.
+pub fn basic_proxy_auth_via_htpasswd<T>(
+    req: &Request<T>,
+    db: &PasswordDB,
+) -> Result<(), AuthError> {
+    basic_auth(req, db, Party::Proxy, Charset::Utf8)
+}
+
+/// Like `basic_proxy_auth_via_htpasswd`, but decodes the credentials
+/// according to `charset`.
+pub fn basic_proxy_auth_via_htpasswd_as<T>(
+    req: &Request<T>,
+    db: &PasswordDB,
+    charset: Charset,
+) -> Result<(), AuthError> {
+    basic_auth(req, db, Party::Proxy, charset)
+}
+
+/// Like `basic_auth_via_htpasswd`, but skips the bcrypt verification
+/// for credentials that `cache` has seen validated recently.
+pub fn basic_auth_via_htpasswd_cached<T>(
+    req: &Request<T>,
+    db: &PasswordDB,
+    cache: &CredentialCache,
+) -> Result<(), AuthError> {
+    basic_auth_via_htpasswd_cached_as(req, db, cache, Charset::Utf8)
+}
+
+/// Like `basic_auth_via_htpasswd_cached`, but decodes the credentials
+/// according to `charset`.
+pub fn basic_auth_via_htpasswd_cached_as<T>(
+    req: &Request<T>,
+    db: &PasswordDB,
+    cache: &CredentialCache,
+    charset: Charset,
+) -> Result<(), AuthError> {
+    let (user, password) = basic_credentials(req, db, Party::Origin, charset)?;
+    cache.validate(db, &user, &password)
+}
+
+/// Like `basic_auth_via_htpasswd`, but runs the password verification
+/// on `pool` instead of blocking the calling thread, and returns a
+/// future that resolves to the result.
+pub fn basic_auth_via_htpasswd_async<T>(
+    req: &Request<T>,
+    db: &PasswordDB,
+    pool: &ValidationPool,
+) -> ValidationFuture {
+    basic_auth_via_htpasswd_async_as(req, db, pool, Charset::Utf8)
+}
+
+/// Like `basic_auth_via_htpasswd_async`, but decodes the credentials
+/// according to `charset`.
+pub fn basic_auth_via_htpasswd_async_as<T>(
+    req: &Request<T>,
+    db: &PasswordDB,
+    pool: &ValidationPool,
+    charset: Charset,
+) -> ValidationFuture {
+    match basic_credentials(req, db, Party::Origin, charset) {
+        Err(e) => Box::new(futures::future::err(e)),
+        Ok((user, password)) => pool.validate(db, &user, &password),
+    }
+}
+
+/// What `Authenticate` does with a request once the credentials it
+/// carries have been validated, possibly on another thread.
+struct Outcome<B> {
+    user: String,
+    realm: String,
+    charset: Charset,
+    party: Party,
+    throttle: Option<(Arc<Throttle>, IpAddr)>,
+    groups: Option<GroupLookup>,
+    strip_authorization: bool,
+    response_hook: Option<ResponseHook<B>>,
+}
+
+impl<B> Outcome<B> {
+    /// Returns the request to pass upstream if the credentials are
+    /// valid, or else the response to send instead.
+    fn settle(
+        self,
+        mut request: Request<B>,
+        result: Result<(), AuthError>,
+    ) -> Result<Request<B>, Response<Body>> {
+        if let Some((throttle, client)) = &self.throttle {
+            throttle.finish(&self.user, client, &result);
+        }
+        match result {
+            Ok(()) => {
+                forward_as(
+                    &mut request,
+                    self.user,
+                    &self.groups,
+                    self.party,
+                    self.strip_authorization,
+                );
+                if self.party == Party::Proxy {
+                    request.headers_mut().remove(header::PROXY_AUTHORIZATION);
+                }
+                Ok(request)
+            }
+            Err(e) => Err(reject_credentials(
+                &request,
+                &e,
+                self.party,
+                &self.realm,
+                self.charset,
+                &self.response_hook,
+            )),
+        }
+    }
+}
+
+/// Responds to a request whose credentials weren't accepted.
+fn reject_credentials<B>(
+    request: &Request<B>,
+    error: &AuthError,
+    party: Party,
+    realm: &str,
+    charset: Charset,
+    hook: &Option<ResponseHook<B>>,
+) -> Response<Body> {
+    let (status, retry_after) = match error {
+        AuthError::Throttled(retry_after) => (StatusCode::TOO_MANY_REQUESTS, Some(*retry_after)),
+        AuthError::Overloaded => (StatusCode::SERVICE_UNAVAILABLE, None),
+        _ => (party.unauthorized(), None),
+    };
+    let rejection = Rejection {
+        status,
+        error: Some(error),
+    };
+    reject(
+        request,
+        &rejection,
+        hook,
+        Some((party.challenge_header(), basic_challenge(realm, charset))),
+        retry_after,
+    )
+}
+
+/// Passes a request upstream, or rejects it, once `validation` is
+/// done.
+type Defer<T> = fn(
+    &T,
+    ValidationFuture,
+    Request<<T as Service>::ReqBody>,
+    Outcome<<T as Service>::ReqBody>,
+) -> Deferred<<T as Service>::Error>;
+
+fn defer<T>(
+    upstream: &T,
+    validation: ValidationFuture,
+    request: Request<T::ReqBody>,
+    outcome: Outcome<T::ReqBody>,
+) -> Deferred<T::Error>
+where
+    T: Service<ResBody = Body> + Clone + Send + 'static,
+    T::ReqBody: Send + 'static,
+    T::Future: Send + 'static,
+    T::Error: Send + 'static,
+{
+    let mut upstream = upstream.clone();
+    Box::new(
+        validation.then(move |result| match outcome.settle(request, result) {
+            Ok(request) => Either::A(upstream.call(request)),
+            Err(response) => Either::B(futures::future::ok(response)),
+        }),
+    )
+}
+
+/// Returns the HTTP Digest credentials sent with a request.
+fn digest_credentials<T>(req: &Request<T>) -> Result<DigestCredentials, AuthError> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(DigestCredentials::parse)
+        .ok_or(AuthError::NotAuthenticated(BadCredentials::InvalidPassword))
+}
+
+/// Authenticates a request to the server using the HTTP Digest
+/// Authorization protocol (RFC 7616) against a DB loaded from an
+/// htdigest file, accepting users in `realm` only. The nonce in the
+/// credentials must have been issued by `nonces`.
+pub fn digest_auth_via_htdigest<T>(
+    req: &Request<T>,
+    db: &DigestDB,
+    realm: &str,
+    nonces: &Nonces,
+) -> Result<(), AuthError> {
+    verify_digest(req, db, realm, nonces).map(|_| ())
+}
+
+fn verify_digest<T>(
+    req: &Request<T>,
+    db: &DigestDB,
+    realm: &str,
+    nonces: &Nonces,
+) -> Result<DigestCredentials, AuthError> {
+    let credentials = digest_credentials(req)?;
+    let uri = req.uri();
+    let same_uri = credentials.uri == uri.to_string()
+        || uri
+            .path_and_query()
+            .map_or(false, |path| path.as_str() == credentials.uri);
+    if !same_uri {
+        return Err(AuthError::NotAuthenticated(BadCredentials::InvalidPassword));
     }
+    db.verify(&credentials, realm, req.method().as_str(), nonces)?;
+    Ok(credentials)
 }
 
 pub struct Authenticate<T, S>
 where
     S: PasswordDBSource,
-    T: Service,
-    T::Future: Into<FutureResult<Response<Body>, hyper::Error>>,
+    T: Service<ResBody = Body>,
 {
     upstream: T,
     source: S,
+    realm: String,
+    cache: Option<Arc<CredentialCache>>,
+    throttle: Option<(Arc<Throttle>, IpAddr)>,
+    rules: Option<Arc<Rules>>,
+    default_access: Access,
+    groups: Option<GroupLookup>,
+    strip_authorization: bool,
+    party: Party,
+    charset: Charset,
+    pool: Option<(ValidationPool, Defer<T>)>,
+    load_errors: LoadErrorHandling,
+    response_hook: Option<ResponseHook<T::ReqBody>>,
 }
 
 impl<T, S> Authenticate<T, S>
 where
     S: PasswordDBSource,
-    T: Service,
-    T::Future: Into<FutureResult<Response<Body>, hyper::Error>>,
+    T: Service<ResBody = Body>,
 {
     pub fn new(upstream: T, source: S) -> Self {
-        Authenticate { upstream, source }
+        Authenticate {
+            upstream,
+            source,
+            realm: DEFAULT_REALM.to_string(),
+            cache: None,
+            throttle: None,
+            rules: None,
+            default_access: Access::default(),
+            groups: None,
+            strip_authorization: false,
+            party: Party::Origin,
+            charset: Charset::default(),
+            pool: None,
+            load_errors: LoadErrorHandling::default(),
+            response_hook: None,
+        }
+    }
+
+    /// Sets the realm that clients are asked to log in to, which
+    /// browsers show in their login prompt. Defaults to
+    /// `DEFAULT_REALM`.
+    ///
+    /// # Panics
+    ///
+    /// If the realm isn't printable ASCII (see
+    /// `htpasswd::is_valid_realm`).
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = checked_realm(realm);
+        self
+    }
+
+    /// Remembers successfully validated credentials in `cache`, which
+    /// can be shared between the `Authenticate` services of all
+    /// connections.
+    pub fn cache_credentials(mut self, cache: Arc<CredentialCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Delays further login attempts after repeated failures, for the
+    /// user name being tried as well as for the client's IP address,
+    /// responding with `429 Too Many Requests`. The `throttle` should
+    /// be shared between the `Authenticate` services of all
+    /// connections, each constructed with the connection's remote
+    /// address.
+    pub fn throttle(mut self, throttle: Arc<Throttle>, client: IpAddr) -> Self {
+        self.throttle = Some((throttle, client));
+        self
+    }
+
+    /// Decides per request whether to check credentials, let it
+    /// through anonymously or deny it, according to the first of the
+    /// `rules` that matches. Without rules, every request needs
+    /// credentials (unless `optional` is set).
+    ///
+    /// # Panics
+    ///
+    /// If the realm of an `Access::Authenticate` or `Access::Optional`
+    /// isn't printable ASCII (see `htpasswd::is_valid_realm`).
+    pub fn rules(mut self, rules: Arc<Rules>) -> Self {
+        for access in rules.actions() {
+            if let Access::Authenticate(Some(realm)) | Access::Optional(Some(realm)) = access {
+                checked_realm(realm);
+            }
+        }
+        self.rules = Some(rules);
+        self
+    }
+
+    /// Passes requests without an `Authorization` header upstream
+    /// anonymously, i.e. without an `AuthenticatedUser`, while still
+    /// rejecting requests with wrong credentials. This applies to all
+    /// requests unless `rules` are set; use `Access::Optional` in them
+    /// instead.
+    pub fn optional(mut self) -> Self {
+        self.default_access = Access::Optional(None);
+        self
+    }
+
+    /// Looks up the groups of authenticated users in `groups`, to
+    /// pass them upstream in the `AuthenticatedUser`.
+    pub fn groups<G>(mut self, groups: G) -> Self
+    where
+        G: GroupDBSource + Send + Sync + 'static,
+    {
+        self.groups = Some(group_lookup(groups));
+        self
+    }
+
+    /// Removes the `Authorization` header (and with it, the password)
+    /// from authenticated requests before passing them upstream. In
+    /// proxy mode, the `Proxy-Authorization` header is removed anyway,
+    /// and `Authorization` is always passed on.
+    pub fn strip_authorization(mut self) -> Self {
+        self.strip_authorization = true;
+        self
+    }
+
+    /// Authenticates clients to a (forward) proxy instead of an origin
+    /// server: credentials are read from the `Proxy-Authorization`
+    /// header, which is removed from every request passed upstream,
+    /// and clients without valid credentials get `407 Proxy
+    /// Authentication Required` with a `Proxy-Authenticate`
+    /// challenge. The `Authorization` header is left alone, since it
+    /// is meant for the origin server.
+    pub fn proxy(mut self) -> Self {
+        self.party = Party::Proxy;
+        self
+    }
+
+    /// Decodes credentials according to `charset`. Defaults to
+    /// `Charset::Utf8`, which challenges announce to clients; with
+    /// other charsets, they leave the `charset` parameter out.
+    pub fn charset(mut self, charset: Charset) -> Self {
+        self.charset = charset;
+        self
+    }
+
+    /// Passes errors from loading the password DB to `hook`, e.g. to
+    /// log them. Clients only ever see a generic error response.
+    pub fn on_load_error<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&dyn Error) + Send + Sync + 'static,
+    {
+        self.load_errors.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Responds with `503 Service Unavailable` instead of `500
+    /// Internal Server Error` when the password DB can't be loaded,
+    /// optionally telling clients when to retry.
+    pub fn respond_unavailable(mut self, retry_after: Option<Duration>) -> Self {
+        self.load_errors.status = StatusCode::SERVICE_UNAVAILABLE;
+        self.load_errors.retry_after = retry_after;
+        self
+    }
+
+    /// Builds the responses to rejected requests with `hook` instead
+    /// of `default_response`, e.g. to render an HTML page for
+    /// browsers and JSON for API clients, depending on the request's
+    /// `Accept` header. If a `401 Unauthorized` response lacks a
+    /// `WWW-Authenticate` header (or a `407` response in proxy mode a
+    /// `Proxy-Authenticate` header), the challenge is added, and so is
+    /// `Retry-After` when the client is told to retry later.
+    pub fn respond_with<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Request<T::ReqBody>, &Rejection) -> Response<Body> + Send + Sync + 'static,
+    {
+        self.response_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Takes a look at the credentials of a request that needs them,
+    /// and starts validating them.
+    fn authenticate(
+        &mut self,
+        request: Request<T::ReqBody>,
+        db: &PasswordDB,
+        realm: String,
+    ) -> ResponseFuture<T::Future> {
+        let (user, password) = match basic_credentials(&request, db, self.party, self.charset) {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                let response = reject_credentials(
+                    &request,
+                    &e,
+                    self.party,
+                    &realm,
+                    self.charset,
+                    &self.response_hook,
+                );
+                return ResponseFuture::rejected(response);
+            }
+        };
+        // Credentials that the cache accepts don't need throttling:
+        let throttle = match (&self.throttle, &self.cache) {
+            (Some(_), Some(cache)) if cache.contains(db, &user, &password) => None,
+            (throttle, _) => throttle.clone(),
+        };
+        if let Some((throttle, client)) = &throttle {
+            if let Err(e) = throttle.begin(&user, client) {
+                let response = reject_credentials(
+                    &request,
+                    &e,
+                    self.party,
+                    &realm,
+                    self.charset,
+                    &self.response_hook,
+                );
+                return ResponseFuture::rejected(response);
+            }
+        }
+        let outcome = Outcome {
+            user,
+            realm,
+            charset: self.charset,
+            party: self.party,
+            throttle,
+            groups: self.groups.clone(),
+            strip_authorization: self.strip_authorization,
+            response_hook: self.response_hook.clone(),
+        };
+        if let Some((pool, defer)) = &self.pool {
+            let validation = match &self.cache {
+                Some(cache) => pool.validate_cached(cache, db, &outcome.user, &password),
+                None => pool.validate(db, &outcome.user, &password),
+            };
+            return ResponseFuture::deferred(defer(&self.upstream, validation, request, outcome));
+        }
+        let result = match &self.cache {
+            Some(cache) => cache.validate(db, &outcome.user, &password),
+            None => db.validate(&outcome.user, &password),
+        };
+        match outcome.settle(request, result) {
+            Ok(request) => ResponseFuture::upstream(self.upstream.call(request)),
+            Err(response) => ResponseFuture::rejected(response),
+        }
+    }
+
+    /// Passes a request upstream, without the credentials meant for
+    /// the proxy if this is one.
+    fn forward(&mut self, mut request: Request<T::ReqBody>) -> ResponseFuture<T::Future> {
+        if self.party == Party::Proxy {
+            request.headers_mut().remove(header::PROXY_AUTHORIZATION);
+        }
+        ResponseFuture::upstream(self.upstream.call(request))
+    }
+}
+
+impl<T, S> Authenticate<T, S>
+where
+    S: PasswordDBSource,
+    T: Service<ResBody = Body> + Clone + Send + 'static,
+    T::ReqBody: Send + 'static,
+    T::Future: Send + 'static,
+    T::Error: Send + 'static,
+{
+    /// Validates credentials on `pool` instead of the thread that
+    /// calls the service, which is typically running an event loop
+    /// that shouldn't be kept busy by bcrypt. Requests are passed to
+    /// a clone of the upstream service once their credentials are
+    /// validated; if the pool is overloaded, clients get `503 Service
+    /// Unavailable`.
+    pub fn pool(mut self, pool: ValidationPool) -> Self {
+        self.pool = Some((pool, defer::<T>));
+        self
     }
 }
 
 impl<T, S> Service for Authenticate<T, S>
 where
     S: PasswordDBSource,
-    T: Service,
-    T::Future: Into<FutureResult<Response<Body>, hyper::Error>>,
+    T: Service<ResBody = Body>,
 {
     type ReqBody = T::ReqBody;
     type ResBody = Body;
-    type Error = hyper::Error;
-    type Future = FutureResult<Response<Body>, hyper::Error>;
+    type Error = T::Error;
+    type Future = ResponseFuture<T::Future>;
 
     fn call(&mut self, request: Request<Self::ReqBody>) -> Self::Future {
+        let access = match &self.rules {
+            Some(rules) => rules.action(&request).clone(),
+            None => self.default_access.clone(),
+        };
+        let realm = match access {
+            Access::Authenticate(realm) => realm.unwrap_or_else(|| self.realm.clone()),
+            Access::Optional(_)
+                if !request
+                    .headers()
+                    .contains_key(self.party.credentials_header()) =>
+            {
+                return self.forward(request);
+            }
+            Access::Optional(realm) => realm.unwrap_or_else(|| self.realm.clone()),
+            Access::Anonymous => return self.forward(request),
+            Access::Deny => {
+                let rejection = Rejection {
+                    status: StatusCode::FORBIDDEN,
+                    error: None,
+                };
+                return ResponseFuture::rejected(reject(
+                    &request,
+                    &rejection,
+                    &self.response_hook,
+                    None,
+                    None,
+                ));
+            }
+        };
+        match self.source.get().deref() {
+            Ok(db) => self.authenticate(request, db, realm),
+            Err(e) => {
+                self.load_errors.report(e);
+                let rejection = Rejection {
+                    status: self.load_errors.status,
+                    error: None,
+                };
+                ResponseFuture::rejected(reject(
+                    &request,
+                    &rejection,
+                    &self.response_hook,
+                    None,
+                    self.load_errors.retry_after,
+                ))
+            }
+        }
+    }
+}
+
+/// Like `Authenticate`, but for HTTP Digest authentication against a
+/// DB loaded from an htdigest file. Unauthenticated requests are
+/// answered with a challenge carrying a fresh nonce.
+pub struct AuthenticateDigest<T, S>
+where
+    S: DigestDBSource,
+    T: Service<ResBody = Body>,
+{
+    upstream: T,
+    source: S,
+    realm: String,
+    nonces: Arc<Nonces>,
+    groups: Option<GroupLookup>,
+    strip_authorization: bool,
+    load_errors: LoadErrorHandling,
+    response_hook: Option<ResponseHook<T::ReqBody>>,
+}
+
+impl<T, S> AuthenticateDigest<T, S>
+where
+    S: DigestDBSource,
+    T: Service<ResBody = Body>,
+{
+    /// Creates a service that lets through requests authenticated for
+    /// `realm`. The `nonces` should be shared between the services of
+    /// all connections, so that a nonce that was issued on one
+    /// connection can be used on another.
+    ///
+    /// # Panics
+    ///
+    /// If the realm isn't printable ASCII (see
+    /// `htpasswd::is_valid_realm`).
+    pub fn new(upstream: T, source: S, realm: &str, nonces: Arc<Nonces>) -> Self {
+        AuthenticateDigest {
+            upstream,
+            source,
+            realm: checked_realm(realm),
+            nonces,
+            groups: None,
+            strip_authorization: false,
+            load_errors: LoadErrorHandling::default(),
+            response_hook: None,
+        }
+    }
+
+    /// Looks up the groups of authenticated users in `groups`, like
+    /// `Authenticate::groups`.
+    pub fn groups<G>(mut self, groups: G) -> Self
+    where
+        G: GroupDBSource + Send + Sync + 'static,
+    {
+        self.groups = Some(group_lookup(groups));
+        self
+    }
+
+    /// Removes the `Authorization` header from authenticated requests
+    /// before passing them upstream.
+    pub fn strip_authorization(mut self) -> Self {
+        self.strip_authorization = true;
+        self
+    }
+
+    /// Passes errors from loading the htdigest DB to `hook`, like
+    /// `Authenticate::on_load_error`.
+    pub fn on_load_error<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&dyn Error) + Send + Sync + 'static,
+    {
+        self.load_errors.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Responds with `503 Service Unavailable` when the htdigest DB
+    /// can't be loaded, like `Authenticate::respond_unavailable`.
+    pub fn respond_unavailable(mut self, retry_after: Option<Duration>) -> Self {
+        self.load_errors.status = StatusCode::SERVICE_UNAVAILABLE;
+        self.load_errors.retry_after = retry_after;
+        self
+    }
+
+    /// Builds the responses to rejected requests with `hook`, like
+    /// `Authenticate::respond_with`.
+    pub fn respond_with<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Request<T::ReqBody>, &Rejection) -> Response<Body> + Send + Sync + 'static,
+    {
+        self.response_hook = Some(Arc::new(hook));
+        self
+    }
+}
+
+impl<T, S> Service for AuthenticateDigest<T, S>
+where
+    S: DigestDBSource,
+    T: Service<ResBody = Body>,
+{
+    type ReqBody = T::ReqBody;
+    type ResBody = Body;
+    type Error = T::Error;
+    type Future = ResponseFuture<T::Future>;
+
+    fn call(&mut self, mut request: Request<Self::ReqBody>) -> Self::Future {
         match self.source.get().deref() {
-            Ok(db) => {
-                if !basic_auth_via_htpasswd(&request, &db).is_ok() {
-                    return futures::future::ok(
-                        Response::builder()
-                            .status(StatusCode::UNAUTHORIZED)
-                            .body(Body::from("Unauthorized."))
-                            .expect("Response should build"),
+            Ok(db) => match verify_digest(&request, db, &self.realm, &self.nonces) {
+                Ok(credentials) => {
+                    forward_as(
+                        &mut request,
+                        credentials.username,
+                        &self.groups,
+                        Party::Origin,
+                        self.strip_authorization,
                     );
-                } else {
-                    self.upstream.call(request).into()
+                    ResponseFuture::upstream(self.upstream.call(request))
                 }
-            }
+                Err(e) => {
+                    let stale = e == AuthError::NotAuthenticated(BadCredentials::StaleNonce);
+                    let rejection = Rejection {
+                        status: StatusCode::UNAUTHORIZED,
+                        error: Some(&e),
+                    };
+                    ResponseFuture::rejected(reject(
+                        &request,
+                        &rejection,
+                        &self.response_hook,
+                        Some((
+                            header::WWW_AUTHENTICATE,
+                            self.nonces.challenge(&self.realm, stale),
+                        )),
+                        None,
+                    ))
+                }
+            },
             Err(e) => {
-                return futures::future::ok(
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Body::from(e.to_string()))
-                        .expect("Error"),
-                );
+                self.load_errors.report(e);
+                let rejection = Rejection {
+                    status: self.load_errors.status,
+                    error: None,
+                };
+                ResponseFuture::rejected(reject(
+                    &request,
+                    &rejection,
+                    &self.response_hook,
+                    None,
+                    self.load_errors.retry_after,
+                ))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::FutureResult;
+    use futures::{Future, Stream};
+    use htpasswd::{parse_htgroup_str, parse_htpasswd_str, GroupDB, LoadFailure};
+    use std::io;
+    use std::sync::Mutex;
+
+    /// A source that never changes.
+    type Loaded<T> = Arc<Result<T, LoadFailure>>;
+
+    fn passwords() -> Loaded<PasswordDB> {
+        Arc::new(Ok(parse_htpasswd_str(
+            "asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96",
+        )
+        .unwrap()))
+    }
+
+    fn groups() -> Loaded<GroupDB> {
+        Arc::new(Ok(parse_htgroup_str("admins: asf\nstaff: bsf\n").unwrap()))
+    }
+
+    /// Answers with the identity it was passed, and whether the
+    /// request still has an `Authorization` header.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service for Echo {
+        type ReqBody = Body;
+        type ResBody = Body;
+        type Error = hyper::Error;
+        type Future = FutureResult<Response<Body>, hyper::Error>;
+
+        fn call(&mut self, request: Request<Body>) -> Self::Future {
+            futures::future::ok(Response::new(Body::from(format!(
+                "{:?} {}",
+                request.extensions().get::<AuthenticatedUser>(),
+                request.headers().contains_key(header::AUTHORIZATION)
+            ))))
+        }
+    }
+
+    fn get(authorization: Option<&str>) -> Request<Body> {
+        let mut request = Request::get("/private");
+        if let Some(authorization) = authorization {
+            request.header(header::AUTHORIZATION, authorization);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn body(response: Response<Body>) -> String {
+        let bytes = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    // "asf:oink" and "asf:wrong":
+    const CORRECT: &str = "Basic YXNmOm9pbms=";
+    const WRONG: &str = "Basic YXNmOndyb25n";
+
+    #[test]
+    fn challenges() {
+        assert_eq!(
+            "Basic realm=\"Restricted\", charset=\"UTF-8\"",
+            basic_challenge(DEFAULT_REALM, Charset::Utf8)
+        );
+        assert_eq!(
+            "Basic realm=\"Jane's \\\"stuff\\\"\", charset=\"UTF-8\"",
+            basic_challenge("Jane's \"stuff\"", Charset::Utf8)
+        );
+
+        let mut service = Authenticate::new(Echo, passwords()).realm("Private");
+        for authorization in &[None, Some(WRONG)] {
+            let response = service.call(get(*authorization)).wait().unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+            assert_eq!(
+                "Basic realm=\"Private\", charset=\"UTF-8\"",
+                response.headers()[header::WWW_AUTHENTICATE]
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "must be printable ASCII")]
+    fn bad_realm() {
+        Authenticate::new(Echo, passwords()).realm("Private\r\nX-Injected: 1");
+    }
+
+    #[test]
+    #[should_panic(expected = "must be printable ASCII")]
+    fn bad_rule_realm() {
+        let rules = Rules::new(Access::Authenticate(None)).rule(Rule::prefix(
+            "/admin",
+            Access::Optional(Some("Admins\nX-Injected: 1".to_string())),
+        ));
+        Authenticate::new(Echo, passwords()).rules(Arc::new(rules));
+    }
+
+    #[test]
+    fn forwards_identity() {
+        let mut service = Authenticate::new(Echo, passwords());
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            "Some(AuthenticatedUser { username: \"asf\", groups: None }) true",
+            body(response)
+        );
+
+        let mut service = Authenticate::new(Echo, passwords())
+            .groups(groups())
+            .strip_authorization();
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(
+            "Some(AuthenticatedUser { username: \"asf\", groups: Some([\"admins\"]) }) false",
+            body(response)
+        );
+    }
+
+    #[test]
+    fn hides_load_errors() {
+        let broken = || -> Loaded<PasswordDB> {
+            let error = io::Error::new(io::ErrorKind::Other, "/etc/secret");
+            Arc::new(Err(LoadFailure::Io(error)))
+        };
+        let mut service = Authenticate::new(Echo, broken());
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        assert_eq!("Internal Server Error.", body(response));
+
+        let logged = Arc::new(Mutex::new(vec![]));
+        let log = logged.clone();
+        let mut service = Authenticate::new(Echo, broken())
+            .on_load_error(move |e| log.lock().unwrap().push(e.to_string()))
+            .respond_unavailable(Some(Duration::from_millis(1500)));
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!("2", response.headers()[header::RETRY_AFTER]);
+        assert_eq!("Service Unavailable.", body(response));
+        assert_eq!(
+            vec!["loading htpasswd data: reading: /etc/secret".to_string()],
+            *logged.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn custom_responses() {
+        let mut service =
+            Authenticate::new(Echo, passwords()).respond_with(|request, rejection| {
+                let wants_json = request
+                    .headers()
+                    .get(header::ACCEPT)
+                    .map_or(false, |accept| accept == "application/json");
+                let body = if wants_json {
+                    format!("{{\"status\":{}}}", rejection.status.as_u16())
+                } else {
+                    "<h1>Please log in</h1>".to_string()
+                };
+                Response::builder()
+                    .status(rejection.status)
+                    .body(Body::from(body))
+                    .unwrap()
+            });
+
+        let response = service.call(get(Some(WRONG))).wait().unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!(
+            "Basic realm=\"Restricted\", charset=\"UTF-8\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+        assert_eq!("<h1>Please log in</h1>", body(response));
+
+        let mut request = get(Some(WRONG));
+        request
+            .headers_mut()
+            .insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+        let response = service.call(request).wait().unwrap();
+        assert_eq!("{\"status\":401}", body(response));
+    }
+
+    #[test]
+    fn rules() {
+        let rules = Rules::new(Access::Anonymous)
+            .rule(Rule::prefix("/healthz", Access::Anonymous))
+            .rule(Rule::prefix(
+                "/admin",
+                Access::Authenticate(Some("Admins".to_string())),
+            ))
+            .rule(Rule::prefix("/", Access::Deny).methods(&[hyper::Method::DELETE]))
+            .rule(Rule::prefix("/", Access::Authenticate(None)));
+        let mut service = Authenticate::new(Echo, passwords()).rules(Arc::new(rules));
+        let call = |service: &mut Authenticate<_, _>, method: hyper::Method, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+            service.call(request).wait().unwrap()
+        };
+
+        let response = call(&mut service, hyper::Method::GET, "/healthz");
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("None false", body(response));
+
+        let response = call(&mut service, hyper::Method::GET, "/admin/users");
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!(
+            "Basic realm=\"Admins\", charset=\"UTF-8\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+
+        let response = call(&mut service, hyper::Method::GET, "/pages/1");
+        assert_eq!(
+            "Basic realm=\"Restricted\", charset=\"UTF-8\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+
+        let response = call(&mut service, hyper::Method::DELETE, "/pages/1");
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert_eq!("Forbidden.", body(response));
+    }
+
+    #[test]
+    fn authorization() {
+        let rules = Rules::new("valid-user".parse().unwrap())
+            .rule(Rule::prefix("/public", Require::AllGranted))
+            .rule(Rule::prefix("/admin", "group admins".parse().unwrap()))
+            .rule(Rule::prefix("/bob", "user bob".parse().unwrap()));
+        let authorize = Authorize::new(Echo, Arc::new(rules)).groups(groups());
+        let authenticate_rules =
+            Rules::new(Access::Authenticate(None)).rule(Rule::prefix("/public", Access::Anonymous));
+        let mut service =
+            Authenticate::new(authorize, passwords()).rules(Arc::new(authenticate_rules));
+        let mut call = |path: &str, authorization: &str| {
+            let mut request = Request::get(path);
+            request.header(header::AUTHORIZATION, authorization);
+            service
+                .call(request.body(Body::empty()).unwrap())
+                .wait()
+                .unwrap()
+                .status()
+        };
+
+        assert_eq!(StatusCode::OK, call("/public", "none"));
+        assert_eq!(StatusCode::OK, call("/pages", CORRECT));
+        assert_eq!(StatusCode::OK, call("/admin", CORRECT));
+        assert_eq!(StatusCode::FORBIDDEN, call("/bob", CORRECT));
+        assert_eq!(StatusCode::UNAUTHORIZED, call("/bob", WRONG));
+    }
+
+    #[test]
+    fn optional() {
+        let mut service = Authenticate::new(Echo, passwords()).optional();
+        let response = service.call(get(None)).wait().unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("None false", body(response));
+
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(
+            "Some(AuthenticatedUser { username: \"asf\", groups: None }) true",
+            body(response)
+        );
+
+        let response = service.call(get(Some(WRONG))).wait().unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    /// Answers with the user's name, after a round trip through
+    /// another thread.
+    struct Lookup;
+
+    impl Service for Lookup {
+        type ReqBody = Body;
+        type ResBody = Body;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Response<Body>, Error = io::Error> + Send>;
+
+        fn call(&mut self, request: Request<Body>) -> Self::Future {
+            let user = request.extensions().get::<AuthenticatedUser>().cloned();
+            let (sender, receiver) = futures::sync::oneshot::channel();
+            std::thread::spawn(move || sender.send(user.map(|user| user.username)));
+            Box::new(
+                receiver
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                    .and_then(|username| match username {
+                        Some(username) => Ok(Response::new(Body::from(username))),
+                        None => Err(io::Error::new(io::ErrorKind::NotFound, "no user")),
+                    }),
+            )
+        }
+    }
+
+    #[test]
+    fn async_upstream() {
+        let mut service = Authenticate::new(Lookup, passwords()).optional();
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("asf", body(response));
+
+        let response = service.call(get(Some(WRONG))).wait().unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let error = service.call(get(None)).wait().unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+    }
+
+    #[test]
+    fn pool() {
+        let mut service = Authenticate::new(Echo, passwords()).pool(ValidationPool::new(1));
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(
+            "Some(AuthenticatedUser { username: \"asf\", groups: None }) true",
+            body(response)
+        );
+        let response = service.call(get(Some(WRONG))).wait().unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!(
+            "Basic realm=\"Restricted\", charset=\"UTF-8\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+
+        let cache = Arc::new(CredentialCache::new(Duration::from_secs(60), 10));
+        let mut service = Authenticate::new(Echo, passwords())
+            .cache_credentials(cache.clone())
+            .pool(ValidationPool::new(1).max_in_flight(0));
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn throttling() {
+        let throttle = Arc::new(Throttle::new().max_failures(2));
+        let client = IpAddr::from([192, 0, 2, 1]);
+        let mut service = Authenticate::new(Echo, passwords())
+            .throttle(throttle.clone(), client)
+            .pool(ValidationPool::new(1));
+        // More correct logins than max_failures are under way at once:
+        let responses: Vec<_> = (0..3).map(|_| service.call(get(Some(CORRECT)))).collect();
+        for response in responses {
+            assert_eq!(StatusCode::OK, response.wait().unwrap().status());
+        }
+
+        let cache = Arc::new(CredentialCache::new(Duration::from_secs(60), 10));
+        let mut service = Authenticate::new(Echo, passwords())
+            .cache_credentials(cache)
+            .throttle(throttle, client);
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        for status in &[
+            StatusCode::UNAUTHORIZED,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let response = service.call(get(Some(WRONG))).wait().unwrap();
+            assert_eq!(*status, response.status());
+        }
+        // Credentials that the cache accepts aren't throttled:
+        let response = service.call(get(Some(CORRECT))).wait().unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    #[test]
+    fn charsets() {
+        let contents = "m\u{fc}ller:$2y$05$sKukuXbHSAEZ6i82T73.Se6mfOAtbWJnWGx6AHd8NIspTCWKVk3N.";
+        let db = parse_htpasswd_str(contents).unwrap();
+        let source =
+            || -> Loaded<PasswordDB> { Arc::new(Ok(parse_htpasswd_str(contents).unwrap())) };
+        // "m\u{fc}ller:p\u{e4}ssword" from a current browser, in UTF-8,
+        // and from an older one, in Latin-1:
+        let utf8 = "Basic bcO8bGxlcjpww6Rzc3dvcmQ=";
+        let latin1 = "Basic bfxsbGVyOnDkc3N3b3Jk";
+
+        assert_eq!(Ok(()), basic_auth_via_htpasswd(&get(Some(utf8)), &db));
+        assert!(basic_auth_via_htpasswd(&get(Some(latin1)), &db).is_err());
+        assert_eq!(
+            Ok(()),
+            basic_auth_via_htpasswd_as(&get(Some(latin1)), &db, Charset::Utf8OrLatin1)
+        );
+        let cache = CredentialCache::new(Duration::from_secs(60), 10);
+        assert_eq!(
+            Ok(()),
+            basic_auth_via_htpasswd_cached_as(
+                &get(Some(latin1)),
+                &db,
+                &cache,
+                Charset::Utf8OrLatin1
+            )
+        );
+        let to_proxy = Request::get("/")
+            .header(header::PROXY_AUTHORIZATION, latin1)
+            .body(Body::empty())
+            .unwrap();
+        assert!(basic_proxy_auth_via_htpasswd(&to_proxy, &db).is_err());
+        assert_eq!(
+            Ok(()),
+            basic_proxy_auth_via_htpasswd_as(&to_proxy, &db, Charset::Utf8OrLatin1)
+        );
+
+        let mut service = Authenticate::new(Echo, source());
+        let response = service.call(get(Some(latin1))).wait().unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let mut service = Authenticate::new(Echo, source()).charset(Charset::Utf8OrLatin1);
+        for authorization in &[utf8, latin1] {
+            let response = service.call(get(Some(*authorization))).wait().unwrap();
+            assert_eq!(
+                "Some(AuthenticatedUser { username: \"m\u{fc}ller\", groups: None }) true",
+                body(response)
+            );
+        }
+        // which clients aren't told to encode in UTF-8:
+        let response = service.call(get(None)).wait().unwrap();
+        assert_eq!(
+            "Basic realm=\"Restricted\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+        let mut authorize = Authorize::new(Echo, Arc::new(Rules::new(Require::ValidUser)))
+            .charset(Charset::Utf8OrLatin1);
+        let response = authorize.call(get(None)).wait().unwrap();
+        assert_eq!(
+            "Basic realm=\"Restricted\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+    }
+
+    #[test]
+    fn proxy() {
+        let upstream = || {
+            hyper::service::service_fn(|request: Request<Body>| {
+                let headers = request.headers();
+                futures::future::ok::<_, hyper::Error>(Response::new(Body::from(format!(
+                    "{:?} {:?} {}",
+                    request.extensions().get::<AuthenticatedUser>(),
+                    headers.get(header::AUTHORIZATION),
+                    headers.contains_key(header::PROXY_AUTHORIZATION)
+                ))))
+            })
+        };
+        let mut service = Authenticate::new(upstream(), passwords())
+            .realm("Proxy")
+            .proxy();
+        let request = |proxy_authorization: &str| {
+            let mut request = Request::get("http://example.com/");
+            request
+                .header(header::AUTHORIZATION, "Bearer origin")
+                .header(header::PROXY_AUTHORIZATION, proxy_authorization);
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = service.call(request(CORRECT)).wait().unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            "Some(AuthenticatedUser { username: \"asf\", groups: None }) Some(\"Bearer origin\") false",
+            body(response)
+        );
+
+        for response in vec![
+            service.call(request(WRONG)),
+            service.call(get(Some(CORRECT))),
+        ] {
+            let response = response.wait().unwrap();
+            assert_eq!(StatusCode::PROXY_AUTHENTICATION_REQUIRED, response.status());
+            assert_eq!(
+                "Basic realm=\"Proxy\", charset=\"UTF-8\"",
+                response.headers()[header::PROXY_AUTHENTICATE]
+            );
+            assert!(!response.headers().contains_key(header::WWW_AUTHENTICATE));
+        }
+
+        // Stripping credentials leaves those meant for the origin:
+        let mut stripping = Authenticate::new(upstream(), passwords())
+            .proxy()
+            .strip_authorization();
+        let response = stripping.call(request(CORRECT)).wait().unwrap();
+        assert_eq!(
+            "Some(AuthenticatedUser { username: \"asf\", groups: None }) Some(\"Bearer origin\") false",
+            body(response)
+        );
+
+        let authorize = Authorize::new(upstream(), Arc::new(Rules::new(Require::ValidUser)))
+            .realm("Proxy")
+            .proxy();
+        let mut authorizing = Authenticate::new(authorize, passwords()).proxy().optional();
+        let response = authorizing.call(get(None)).wait().unwrap();
+        assert_eq!(StatusCode::PROXY_AUTHENTICATION_REQUIRED, response.status());
+        assert_eq!(
+            "Basic realm=\"Proxy\", charset=\"UTF-8\"",
+            response.headers()[header::PROXY_AUTHENTICATE]
+        );
+        assert!(!response.headers().contains_key(header::WWW_AUTHENTICATE));
+
+        let source = passwords();
+        let db = source.as_ref().as_ref().unwrap();
+        let mut request = request(CORRECT);
+        assert_eq!(Ok(()), basic_proxy_auth_via_htpasswd(&request, db));
+        assert!(basic_auth_via_htpasswd(&request, db).is_err());
+        request.headers_mut().remove(header::PROXY_AUTHORIZATION);
+        assert!(basic_proxy_auth_via_htpasswd(&request, db).is_err());
+    }
+}
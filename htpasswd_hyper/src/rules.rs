@@ -0,0 +1,333 @@
+use hyper::{Method, Request};
+
+/// What `Authenticate` does with the requests that a rule matches.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    /// Check the credentials, challenging clients to log in to the
+    /// given realm, or the service's realm if there is none.
+    Authenticate(Option<String>),
+
+    /// Check the credentials if the request has an `Authorization`
+    /// header, like `Authenticate`, but pass requests without one
+    /// upstream anonymously.
+    Optional(Option<String>),
+
+    /// Pass requests upstream without checking credentials.
+    Anonymous,
+
+    /// Reject requests with `403 Forbidden`.
+    Deny,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::Authenticate(None)
+    }
+}
+
+/// The paths that a `Rule` applies to.
+#[derive(Debug, Clone, PartialEq)]
+enum PathPattern {
+    Prefix(String),
+    Glob(String),
+}
+
+impl PathPattern {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Prefix(prefix) => {
+                path.starts_with(prefix.as_str())
+                    && (path.len() == prefix.len()
+                        || prefix.ends_with('/')
+                        || path[prefix.len()..].starts_with('/'))
+            }
+            PathPattern::Glob(pattern) => glob_matches(pattern.as_bytes(), path.as_bytes()),
+        }
+    }
+}
+
+/// Returns `path` the way servers generally resolve it: percent-decoded,
+/// without empty and `.` segments, and with each `..` segment
+/// removing the one before it. Rules match this form, so that e.g.
+/// `//admin`, `/%61dmin` and `/x/../admin` can't get around a rule for
+/// `/admin`.
+fn normalize_path(path: &str) -> String {
+    let decoded = percent_decode(path.as_bytes());
+    let decoded = String::from_utf8_lossy(&decoded);
+    let mut segments = vec![];
+    let mut trailing_slash = false;
+    for segment in decoded.split('/') {
+        trailing_slash = true;
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Decodes the `%XX` escapes in `input`, leaving malformed ones as
+/// they are.
+fn percent_decode(input: &[u8]) -> Vec<u8> {
+    let hex = |c: u8| (c as char).to_digit(16);
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] == b'%' && i + 2 < input.len() {
+            if let (Some(high), Some(low)) = (hex(input[i + 1]), hex(input[i + 2])) {
+                decoded.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(input[i]);
+        i += 1;
+    }
+    decoded
+}
+
+/// Matches a path against a glob, in which `?` matches any character
+/// and `*` any number of characters except for `/`, and `**` matches
+/// any number of characters including `/`.
+fn glob_matches(pattern: &[u8], path: &[u8]) -> bool {
+    if pattern.starts_with(b"**") {
+        return (0..=path.len()).any(|i| glob_matches(&pattern[2..], &path[i..]));
+    }
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(b'*') => {
+            let segment = path
+                .iter()
+                .position(|&c| c == b'/')
+                .unwrap_or_else(|| path.len());
+            (0..=segment).any(|i| glob_matches(&pattern[1..], &path[i..]))
+        }
+        Some(b'?') => match path.first() {
+            Some(&c) if c != b'/' => glob_matches(&pattern[1..], &path[1..]),
+            _ => false,
+        },
+        Some(p) => path.first() == Some(p) && glob_matches(&pattern[1..], &path[1..]),
+    }
+}
+
+/// Applies an action (like an `Access`) to the requests whose path
+/// and method match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule<A = Access> {
+    path: PathPattern,
+    methods: Option<Vec<Method>>,
+    action: A,
+}
+
+impl<A> Rule<A> {
+    /// A rule for the path `prefix` and everything below it: the
+    /// prefix `/admin` matches `/admin` and `/admin/users`, but not
+    /// `/administrator`.
+    pub fn prefix(prefix: &str, action: A) -> Rule<A> {
+        Rule {
+            path: PathPattern::Prefix(prefix.to_string()),
+            methods: None,
+            action,
+        }
+    }
+
+    /// A rule for the paths matching a glob pattern, e.g.
+    /// `/users/*/settings` or `/static/**`.
+    pub fn glob(pattern: &str, action: A) -> Rule<A> {
+        Rule {
+            path: PathPattern::Glob(pattern.to_string()),
+            methods: None,
+            action,
+        }
+    }
+
+    /// Restricts the rule to requests with one of the `methods`.
+    pub fn methods(mut self, methods: &[Method]) -> Rule<A> {
+        self.methods = Some(methods.to_vec());
+        self
+    }
+
+    /// Returns true if the rule applies to the request.
+    pub fn matches<B>(&self, request: &Request<B>) -> bool {
+        self.matches_at(request, &normalize_path(request.uri().path()))
+    }
+
+    /// Returns true if the rule applies to the request, whose path
+    /// has been normalized to `path`.
+    fn matches_at<B>(&self, request: &Request<B>, path: &str) -> bool {
+        let method_matches = self
+            .methods
+            .as_ref()
+            .map_or(true, |methods| methods.contains(request.method()));
+        method_matches && self.path.matches(path)
+    }
+}
+
+/// A table of rules, the first of which that matches a request
+/// decides what happens to it. Rules are matched against the path
+/// after percent-decoding it and resolving empty, `.` and `..`
+/// segments, the way the upstream service is likely to see it:
+///
+/// ```rust
+/// use htpasswd_hyper::{Access, Rule, Rules};
+/// use hyper::Method;
+///
+/// let rules = Rules::new(Access::Anonymous)
+///     .rule(Rule::prefix("/", Access::Anonymous).methods(&[Method::OPTIONS]))
+///     .rule(Rule::prefix("/healthz", Access::Anonymous))
+///     .rule(Rule::prefix("/admin", Access::Authenticate(Some("Admins".to_string()))))
+///     .rule(Rule::glob("/**/.git/**", Access::Deny));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rules<A = Access> {
+    rules: Vec<Rule<A>>,
+    default: A,
+}
+
+impl<A> Rules<A> {
+    /// Creates a table that applies `default` to the requests that no
+    /// rule matches.
+    pub fn new(default: A) -> Rules<A> {
+        Rules {
+            rules: vec![],
+            default,
+        }
+    }
+
+    /// Adds a rule after the existing ones.
+    pub fn rule(mut self, rule: Rule<A>) -> Rules<A> {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Returns the action of the first rule that matches the request,
+    /// or the default.
+    pub fn action<B>(&self, request: &Request<B>) -> &A {
+        let path = normalize_path(request.uri().path());
+        self.rules
+            .iter()
+            .find(|rule| rule.matches_at(request, &path))
+            .map_or(&self.default, |rule| &rule.action)
+    }
+
+    /// Returns the actions of all rules, and the default.
+    pub(crate) fn actions(&self) -> impl Iterator<Item = &A> {
+        self.rules
+            .iter()
+            .map(|rule| &rule.action)
+            .chain(Some(&self.default))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, path: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn prefixes() {
+        let admin = PathPattern::Prefix("/admin".to_string());
+        assert!(admin.matches("/admin"));
+        assert!(admin.matches("/admin/users"));
+        assert!(!admin.matches("/administrator"));
+        assert!(!admin.matches("/"));
+        assert!(PathPattern::Prefix("/".to_string()).matches("/anything"));
+    }
+
+    #[test]
+    fn globs() {
+        let settings = PathPattern::Glob("/users/*/settings".to_string());
+        assert!(settings.matches("/users/asf/settings"));
+        assert!(!settings.matches("/users/asf/bsf/settings"));
+        assert!(!settings.matches("/users/settings"));
+
+        let git = PathPattern::Glob("/**/.git/**".to_string());
+        assert!(git.matches("/repos/x/.git/config"));
+        assert!(!git.matches("/repos/x/git/config"));
+
+        assert!(PathPattern::Glob("/v?/status".to_string()).matches("/v1/status"));
+        assert!(!PathPattern::Glob("/v?/status".to_string()).matches("/v//status"));
+    }
+
+    #[test]
+    fn normalization() {
+        assert_eq!("/admin", normalize_path("/admin"));
+        assert_eq!("/admin", normalize_path("//admin"));
+        assert_eq!("/admin", normalize_path("/%61dmin"));
+        assert_eq!("/admin", normalize_path("/x/../admin"));
+        assert_eq!("/admin", normalize_path("/x/%2e%2E/admin"));
+        assert_eq!("/admin/users", normalize_path("/./admin//./users"));
+        assert_eq!("/admin", normalize_path("/../../admin"));
+        assert_eq!("/admin/", normalize_path("/admin/users/.."));
+        assert_eq!("/", normalize_path("/"));
+        assert_eq!("/", normalize_path(""));
+        assert_eq!("/100%", normalize_path("/100%"));
+        assert_eq!("/a%zz", normalize_path("/a%zz"));
+
+        let rules = Rules::new(Access::Anonymous).rule(Rule::prefix("/admin", Access::Deny));
+        for path in &[
+            "/admin",
+            "//admin",
+            "/%61dmin",
+            "/x/../admin",
+            "/admin/./users",
+        ] {
+            assert_eq!(
+                &Access::Deny,
+                rules.action(&request(Method::GET, path)),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = Rules::new(Access::Anonymous)
+            .rule(Rule::prefix("/", Access::Anonymous).methods(&[Method::OPTIONS]))
+            .rule(Rule::prefix("/healthz", Access::Anonymous))
+            .rule(Rule::prefix(
+                "/admin",
+                Access::Authenticate(Some("Admins".to_string())),
+            ))
+            .rule(Rule::prefix("/", Access::Deny).methods(&[Method::DELETE]));
+
+        assert_eq!(
+            &Access::Anonymous,
+            rules.action(&request(Method::OPTIONS, "/admin/users"))
+        );
+        assert_eq!(
+            &Access::Authenticate(Some("Admins".to_string())),
+            rules.action(&request(Method::GET, "/admin/users"))
+        );
+        assert_eq!(
+            &Access::Deny,
+            rules.action(&request(Method::DELETE, "/pages/1"))
+        );
+        assert_eq!(
+            &Access::Anonymous,
+            rules.action(&request(Method::GET, "/pages/1"))
+        );
+    }
+}
@@ -0,0 +1,403 @@
+//! # `htpasswd_tower` - HTTP Basic authentication for `tower` stacks.
+//!
+//! A `tower` middleware that checks the HTTP Basic credentials of
+//! requests (of the `http` crate's types) against a password DB
+//! loaded from a `.htpasswd` file, answering requests without valid
+//! credentials with `401 Unauthorized`:
+//!
+//! ```rust,ignore
+//! let service = ServiceBuilder::new()
+//!     .layer(HtpasswdLayer::new(source).realm("Private"))
+//!     .timeout(Duration::from_secs(10))
+//!     .service(app);
+//! ```
+//!
+//! Authenticated requests are passed on with an `AuthenticatedUser`
+//! in their extensions.
+
+use futures::future::{self, Either};
+use futures::{Future, Poll};
+use htpasswd::{
+    checked_realm, AuthError, BadCredentials, Charset, PasswordDB, PasswordDBSource, ValidationPool,
+};
+use http::header::{self, HeaderValue};
+use http::{Request, Response, StatusCode};
+use std::mem;
+use std::ops::Deref;
+use std::sync::Arc;
+use tower_layer::Layer;
+use tower_service::Service;
+
+pub use htpasswd::{basic_challenge, AuthenticatedUser, DEFAULT_REALM};
+
+/// Authenticates a request using the HTTP Basic Authorization
+/// protocol against a password DB, returning the user's name. The
+/// credentials must be encoded in UTF-8.
+pub fn basic_auth_via_htpasswd<B>(req: &Request<B>, db: &PasswordDB) -> Result<String, AuthError> {
+    basic_auth_via_htpasswd_as(req, db, Charset::Utf8)
+}
+
+/// Like `basic_auth_via_htpasswd`, but decodes the credentials
+/// according to `charset`.
+pub fn basic_auth_via_htpasswd_as<B>(
+    req: &Request<B>,
+    db: &PasswordDB,
+    charset: Charset,
+) -> Result<String, AuthError> {
+    db.validate_basic_header_as(authorization(req)?.as_bytes(), charset)
+}
+
+/// Returns the value of a request's `Authorization` header.
+fn authorization<B>(req: &Request<B>) -> Result<&HeaderValue, AuthError> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .ok_or(AuthError::NotAuthenticated(BadCredentials::InvalidPassword))
+}
+
+/// A `Layer` that wraps services in `Htpasswd`, sharing the password
+/// DB source between all of them.
+pub struct HtpasswdLayer<S> {
+    source: Arc<S>,
+    realm: Arc<str>,
+    pool: Option<ValidationPool>,
+    charset: Charset,
+}
+
+impl<S> HtpasswdLayer<S>
+where
+    S: PasswordDBSource,
+{
+    pub fn new(source: S) -> Self {
+        HtpasswdLayer {
+            source: Arc::new(source),
+            realm: DEFAULT_REALM.into(),
+            pool: None,
+            charset: Charset::default(),
+        }
+    }
+
+    /// Sets the realm that clients are asked to log in to. Defaults
+    /// to `DEFAULT_REALM`.
+    ///
+    /// # Panics
+    ///
+    /// If the realm isn't printable ASCII (see
+    /// `htpasswd::is_valid_realm`), since it couldn't be sent to
+    /// clients.
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = checked_realm(realm).into();
+        self
+    }
+
+    /// Validates credentials on `pool` rather than on the thread that
+    /// calls the service, and passes requests on once they have been
+    /// validated. Requests that can't be validated because the pool
+    /// is overloaded are answered with `503 Service Unavailable`.
+    pub fn pool(mut self, pool: ValidationPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Decodes credentials according to `charset`. Defaults to
+    /// `Charset::Utf8`, which challenges announce to clients; with
+    /// other charsets, they leave the `charset` parameter out.
+    pub fn charset(mut self, charset: Charset) -> Self {
+        self.charset = charset;
+        self
+    }
+}
+
+impl<S> Clone for HtpasswdLayer<S> {
+    fn clone(&self) -> Self {
+        HtpasswdLayer {
+            source: self.source.clone(),
+            realm: self.realm.clone(),
+            pool: self.pool.clone(),
+            charset: self.charset,
+        }
+    }
+}
+
+impl<S, T> Layer<T> for HtpasswdLayer<S> {
+    type Service = Htpasswd<T, S>;
+
+    fn layer(&self, inner: T) -> Self::Service {
+        Htpasswd {
+            inner,
+            source: self.source.clone(),
+            realm: self.realm.clone(),
+            pool: self.pool.clone(),
+            charset: self.charset,
+        }
+    }
+}
+
+/// A service that passes requests with valid HTTP Basic credentials
+/// on to the `inner` service. Other requests are answered with `401
+/// Unauthorized` and a challenge, with `503 Service Unavailable` if
+/// the validation pool is overloaded, or with `500 Internal Server
+/// Error` if the password DB can't be loaded; these responses have an
+/// empty (default) body.
+pub struct Htpasswd<T, S> {
+    inner: T,
+    source: Arc<S>,
+    realm: Arc<str>,
+    pool: Option<ValidationPool>,
+    charset: Charset,
+}
+
+impl<T: Clone, S> Clone for Htpasswd<T, S> {
+    fn clone(&self) -> Self {
+        Htpasswd {
+            inner: self.inner.clone(),
+            source: self.source.clone(),
+            realm: self.realm.clone(),
+            pool: self.pool.clone(),
+            charset: self.charset,
+        }
+    }
+}
+
+/// The future returned by `Htpasswd`: either the inner service's
+/// response, or a response that `Htpasswd` makes itself, possibly
+/// after validating credentials on a pool.
+pub type ResponseFuture<F, B, E> = Either<F, Box<dyn Future<Item = Response<B>, Error = E> + Send>>;
+
+fn respond<B: Default>(status: StatusCode) -> Response<B> {
+    let mut response = Response::new(B::default());
+    *response.status_mut() = status;
+    response
+}
+
+/// Responds to a request whose credentials weren't accepted.
+fn reject<B: Default>(error: &AuthError, realm: &str, charset: Charset) -> Response<B> {
+    if let AuthError::Overloaded = error {
+        return respond(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let mut response = respond(StatusCode::UNAUTHORIZED);
+    if let Ok(challenge) = HeaderValue::from_str(&basic_challenge(realm, charset)) {
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, challenge);
+    }
+    response
+}
+
+impl<T, S, ReqBody, ResBody> Service<Request<ReqBody>> for Htpasswd<T, S>
+where
+    T: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    T::Future: Send + 'static,
+    T::Error: Send + 'static,
+    S: PasswordDBSource,
+    ReqBody: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = T::Error;
+    type Future = ResponseFuture<T::Future, ResBody, T::Error>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut request: Request<ReqBody>) -> Self::Future {
+        let validation = match (self.source.get().deref(), &self.pool) {
+            (Ok(db), None) => {
+                Validation::Done(basic_auth_via_htpasswd_as(&request, db, self.charset))
+            }
+            (Ok(db), Some(pool)) => {
+                Validation::Pending(validate_on(pool, &request, db, self.charset))
+            }
+            (Err(_), _) => {
+                let response = respond(StatusCode::INTERNAL_SERVER_ERROR);
+                return Either::B(Box::new(future::ok(response)));
+            }
+        };
+        match validation {
+            Validation::Done(Ok(username)) => {
+                request
+                    .extensions_mut()
+                    .insert(AuthenticatedUser::new(username));
+                Either::A(self.inner.call(request))
+            }
+            Validation::Done(Err(e)) => {
+                Either::B(Box::new(future::ok(reject(&e, &self.realm, self.charset))))
+            }
+            Validation::Pending(validation) => {
+                // The clone hasn't been polled ready, so it stays
+                // here, and the instance that has been goes with the
+                // request:
+                let clone = self.inner.clone();
+                let mut inner = mem::replace(&mut self.inner, clone);
+                let realm = self.realm.clone();
+                let charset = self.charset;
+                Either::B(Box::new(validation.then(move |result| match result {
+                    Ok(username) => {
+                        request
+                            .extensions_mut()
+                            .insert(AuthenticatedUser::new(username));
+                        Either::A(inner.call(request))
+                    }
+                    Err(e) => Either::B(future::ok(reject(&e, &realm, charset))),
+                })))
+            }
+        }
+    }
+}
+
+/// The user name in a request's credentials, or the reason it has
+/// none, as far as they have been validated.
+enum Validation {
+    Done(Result<String, AuthError>),
+    Pending(Box<dyn Future<Item = String, Error = AuthError> + Send>),
+}
+
+/// Starts validating the credentials of `request`, decoded according
+/// to `charset`, on `pool`, resolving to the user's name.
+fn validate_on<B>(
+    pool: &ValidationPool,
+    request: &Request<B>,
+    db: &PasswordDB,
+    charset: Charset,
+) -> Box<dyn Future<Item = String, Error = AuthError> + Send> {
+    let credentials = authorization(request)
+        .and_then(|header| Ok(db.decode_basic_header_as(header.as_bytes(), charset)?));
+    match credentials {
+        Ok((user, password)) => Box::new(pool.validate(db, &user, &password).map(|()| user)),
+        Err(e) => Box::new(future::err(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::FutureResult;
+    use futures::Async;
+    use htpasswd::{parse_htpasswd_str, LoadFailure};
+    use std::io;
+
+    /// A source that never changes.
+    type Loaded = Arc<Result<PasswordDB, LoadFailure>>;
+
+    fn passwords() -> Loaded {
+        Arc::new(Ok(parse_htpasswd_str(
+            "asf:$2y$05$6mQlzTSUkBbyHDU7XIwQaO3wOEDZpUdYR4YxRXgM2gqe/nwJSy.96",
+        )
+        .unwrap()))
+    }
+
+    /// Answers with the identity it was passed.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<Request<()>> for Echo {
+        type Response = Response<String>;
+        type Error = io::Error;
+        type Future = FutureResult<Response<String>, io::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, request: Request<()>) -> Self::Future {
+            let user = request.extensions().get::<AuthenticatedUser>();
+            futures::future::ok(Response::new(format!("{:?}", user)))
+        }
+    }
+
+    fn get(authorization: Option<&str>) -> Request<()> {
+        let mut request = Request::get("/private");
+        if let Some(authorization) = authorization {
+            request.header(header::AUTHORIZATION, authorization);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn authenticates() {
+        let layer = HtpasswdLayer::new(passwords()).realm("Private");
+        let mut service = layer.layer(Echo);
+        assert_eq!(Ok(Async::Ready(())), service.poll_ready().map_err(|_| ()));
+
+        // "asf:oink":
+        let response = service
+            .call(get(Some("Basic YXNmOm9pbms=")))
+            .wait()
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            "Some(AuthenticatedUser { username: \"asf\", groups: None })",
+            response.body()
+        );
+
+        // "asf:wrong":
+        for authorization in &[None, Some("Basic YXNmOndyb25n")] {
+            let response = service.call(get(*authorization)).wait().unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+            assert_eq!(
+                "Basic realm=\"Private\", charset=\"UTF-8\"",
+                response.headers()[header::WWW_AUTHENTICATE]
+            );
+            assert_eq!("", response.body());
+        }
+    }
+
+    #[test]
+    fn hides_load_errors() {
+        let error = io::ErrorKind::NotFound.into();
+        let layer = HtpasswdLayer::new(Loaded::new(Err(LoadFailure::Io(error))));
+        let mut service = layer.clone().layer(Echo);
+        let response = service
+            .call(get(Some("Basic YXNmOm9pbms=")))
+            .wait()
+            .unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        assert_eq!("", response.body());
+    }
+
+    #[test]
+    fn pool() {
+        let layer = HtpasswdLayer::new(passwords()).pool(ValidationPool::new(1));
+        let mut service = layer.layer(Echo);
+
+        // "asf:oink":
+        let response = service
+            .call(get(Some("Basic YXNmOm9pbms=")))
+            .wait()
+            .unwrap();
+        assert_eq!(
+            "Some(AuthenticatedUser { username: \"asf\", groups: None })",
+            response.body()
+        );
+
+        // "asf:wrong":
+        for authorization in &[None, Some("Basic YXNmOndyb25n")] {
+            let response = service.call(get(*authorization)).wait().unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+            assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+        }
+
+        let overloaded =
+            HtpasswdLayer::new(passwords()).pool(ValidationPool::new(1).max_in_flight(0));
+        let response = overloaded
+            .layer(Echo)
+            .call(get(Some("Basic YXNmOm9pbms=")))
+            .wait()
+            .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+    }
+
+    #[test]
+    fn charset() {
+        let layer = HtpasswdLayer::new(passwords()).charset(Charset::Utf8OrLatin1);
+        let pooled = layer.clone().pool(ValidationPool::new(1));
+        for mut service in vec![layer.layer(Echo), pooled.layer(Echo)] {
+            // Clients aren't asked to send UTF-8:
+            let response = service.call(get(None)).wait().unwrap();
+            assert_eq!(
+                "Basic realm=\"Restricted\"",
+                response.headers()[header::WWW_AUTHENTICATE]
+            );
+        }
+    }
+}